@@ -1,231 +1,4906 @@
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk_macros::*;  // Keep this for interaction with the ICP environment
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::cell::RefCell;
 use sha2::{Digest, Sha256};  // For creating subaccounts with SHA256
 
 type Subaccount = [u8; 32];  // Defining a type for Subaccount
 type AccountIdentifier = String;
 
-const ICP_FEE: u64 = 10_000;  // Minimum fee for depositing
+const ICP_FEE: u64 = 10_000;  // ICP ledger transfer fee, used until the ledger's own fee is fetched
+const EXPORT_CHUNK_SIZE: usize = 1_000_000;  // Stay well under the 2MB query response limit
+const MAX_BACKUPS: usize = 5;  // Oldest backup is dropped beyond this
+const MAX_LABEL_LEN: usize = 64;  // Characters allowed in a stake label
+const LINK_CHALLENGE_TTL_SECONDS: u64 = 10 * 60;  // How long a link code stays valid
+const MATURITY_JOB_INTERVAL_SECONDS: u64 = 60;  // Heartbeat work runs at most this often
+const MAX_JOBS_PER_RUN: usize = 50;  // Scheduled payouts executed per maturity run
+const MIN_RECURRING_INTERVAL_SECONDS: u64 = 24 * 60 * 60;  // At most one recurring pull per day
+const MAX_NOTIFICATIONS: usize = 100;  // Oldest notification is dropped beyond this
+const MAX_LOCK_SECONDS: u64 = 360 * SECONDS_PER_LOCK_DAY;  // Longest lock tier, earns full voting weight
+const MAX_SNAPSHOTS: usize = 20;  // Oldest snapshot is dropped beyond this
+const MAX_DEAD_LETTERS: usize = 100;  // Oldest dead letter is dropped beyond this
+const STATEMENT_TOKEN_TTL_SECONDS: u64 = 5 * 60;  // How long a statement download link works
+const WEBHOOK_CYCLES: u128 = 200_000_000;  // Covers a small POST on a 13-node subnet; unused cycles are refunded
+const WEBHOOK_MAX_RESPONSE_BYTES: u64 = 2_000;
+const MAX_WEBHOOK_ATTEMPTS: u32 = 5;
+const MAX_WEBHOOK_QUEUE: usize = 100;  // Oldest undelivered alert is dropped beyond this
+const CYCLES_ALERT_INTERVAL_SECONDS: u64 = 60 * 60;  // Repeat the low-cycles alert at most hourly
+const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+#[cfg(not(feature = "faucet"))]
+const SECONDS_PER_LOCK_DAY: u64 = 24 * 60 * 60;
+#[cfg(feature = "faucet")]
+const SECONDS_PER_LOCK_DAY: u64 = 60;  // Test builds run a 90-day lock in 90 minutes
+#[cfg(feature = "faucet")]
+const FAUCET_TOKENS: u32 = 10;  // Whole tokens of simulated balance per drip
+#[cfg(feature = "faucet")]
+const FAUCET_COOLDOWN_SECONDS: u64 = 60 * 60;
+const VOUCHER_TTL_SECONDS: u64 = 90 * 24 * 60 * 60;  // Unredeemed vouchers can be reclaimed after this
+const ACTIVITY_WINDOW_DAYS: u64 = 90;  // Days of per-day volume kept for get_activity_report
+const AUDIT_PAGE_SIZE: usize = 100;  // Subaccounts per audit_accounts page
+const STAKE_RECORD_SIZE: usize = 68;  // id, amount, lock period, stake/unlock time, subaccount
+const MAX_TOKEN_DECIMALS: u8 = 12;  // Amounts are u64 base units, which hold about 18 million whole tokens at 12 decimals
 
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct StakeInfo {
+    pub id: u64,
     pub amount: u64,
     pub lock_period_days: u32,
     pub stake_time: u64,
     pub unlock_time: u64,
     pub subaccount: Subaccount,
+    pub label: Option<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UserStakes {
+    pub stakes: Vec<StakeInfo>,  // Ordered by stake id, since ids only grow
+    pub total_staked: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum StakeStatus {
+    Locked,
+    Unlocked,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StakeView {
+    pub index: usize,  // Position to pass to withdraw and set_stake_label
+    pub info: StakeInfo,
     pub account_id: String,
+    pub status: StakeStatus,
+    pub seconds_until_unlock: u64,
+    pub payout_at_maturity: u64,  // Amount received on withdrawal, after the fee
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UserStakesView {
+    pub stakes: Vec<StakeView>,
+    pub total_staked: u64,
+    pub token: TokenMetadata,
+}
+
+// Display metadata of the staked token, cached from its ICRC-1 ledger
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenMetadata {
+    pub ledger: Principal,
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: u64,  // Ledger transfer fee in base units; also the minimum deposit
+    pub logo_url: Option<String>,
+    pub fetched_at: Option<u64>,  // None until refreshed from the ledger
+}
+
+#[derive(CandidType, Deserialize)]
+enum Icrc1MetadataValue {
+    Nat(candid::Nat),
+    Int(candid::Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Portfolio {
+    pub stakes: Vec<StakeView>,
+    pub total_staked: u64,
+    pub locked_amount: u64,
+    pub unlocked_amount: u64,
+    pub next_unlock_time: Option<u64>,
+    pub preferences: UserPreferences,
+    pub token: TokenMetadata,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PoolStats {
+    pub total_amount: u64,
+    pub total_stakers: u64,
+    pub total_stakes: u64,
+    pub stakes_per_tier: Vec<(u32, u64)>,  // (lock_period_days, count)
+    pub matured_stakes: u64,
+    pub token: TokenMetadata,
+}
+
+// Everything the pool owes, by bucket; each bucket is backed by funds taken in for it
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct BalanceSheet {
+    pub staker_principal: u64,  // Open stakes
+    pub pending_deposits: u64,  // Held by the whale gate, refundable
+    pub voucher_escrow: u64,  // Funded, unredeemed vouchers
+    pub total_liabilities: u64,
+    pub pledged_as_collateral: u64,  // Part of staker_principal under a lien
+}
+
+// One day of deposit and withdrawal volume, built incrementally from history events
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct DayActivity {
+    pub deposit_volume: u64,
+    pub deposit_count: u64,
+    pub withdraw_volume: u64,
+    pub withdraw_count: u64,
+    pub flows: BTreeMap<Principal, (u64, u64)>,  // account -> (deposited, withdrawn)
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DailyActivity {
+    pub day_start: u64,
+    pub deposit_volume: u64,
+    pub deposit_count: u64,
+    pub withdraw_volume: u64,
+    pub withdraw_count: u64,
+    pub active_principals: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ActivityReport {
+    pub days: Vec<DailyActivity>,  // Oldest first; days without activity are omitted
+    pub unique_active_principals: u64,
+    pub top_depositors: Vec<(Principal, u64)>,
+    pub top_withdrawers: Vec<(Principal, u64)>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct ExitQueueStats {
+    pub queued_withdrawals: u64,
+    pub queued_amount: u64,
+    pub average_wait_seconds: u64,  // Until the queued stakes unlock and are paid out
+    pub projected_inflow_30_days: u64,  // Locked stake amount that unlocks within 30 days
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Operation {
+    Deposit,
+    Withdraw,
+    CreateBackup,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct OperationMetrics {
+    pub calls: u64,
+    pub last_instructions: u64,
+    pub max_instructions: u64,
+    pub total_instructions: u64,
+}
+
+// ICRC-1 account
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ScheduledWithdrawal {
+    pub stake_id: u64,
+    pub owner: Principal,
+    pub destination: Account,
+    pub scheduled_by: Principal,
+    pub created_at: u64,
+    pub last_error: Option<String>,  // Set while payouts keep failing; alerted once per distinct error
+}
+
+// Pre-funded stake redeemable with a secret code; only the code's hash is stored
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Voucher {
+    pub id: u64,
+    pub sender: Principal,
+    pub amount: u64,
+    pub lock_period_days: u32,
+    pub code_hash: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+// Claim of a whitelisted lending canister on a stake, which blocks the owner's withdrawal
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Lien {
+    pub stake_id: u64,
+    pub owner: Principal,
+    pub lien_holder: Principal,
+    pub amount: u64,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum RecurringStatus {
+    Active,
+    Paused { reason: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RecurringDeposit {
+    pub id: u64,
+    pub owner: Principal,
+    pub amount: u64,
+    pub interval_seconds: u64,
+    pub lock_period_days: u32,
+    pub allowance_remaining: u64,  // Budget approved by the user, consumed by each pull
+    pub next_run_at: u64,
+    pub status: RecurringStatus,
+    pub deposits_made: u64,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolLifecycle {
+    Bootstrap,  // Deposits only, while the pool builds capital
+    Active,
+    WindDown,  // No new deposits; existing locks run to maturity
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DistributionStatus {
+    pub in_progress: bool,
+    pub stakes_remaining: u64,
+    pub amount_remaining: u64,
+    pub pending_deposits_remaining: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ConcentrationLimit {
+    pub max_share_bps: u32,  // Highest share of the pool one account may hold, in basis points
+    pub min_pool_amount: u64,  // The cap is not enforced while the pool is smaller than this
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ConcentrationStats {
+    pub limit: Option<ConcentrationLimit>,
+    pub total_amount: u64,
+    pub largest_share_bps: u32,
+    pub herfindahl_index_bps: u32,  // Sum of squared shares; 10_000 means a single account holds everything
+    pub top_stakers: Vec<(Principal, u64, u32)>,  // (account, staked amount, share in bps)
+}
+
+// A deposit above the whale gate threshold, held until an admin approves or it is refunded
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PendingDeposit {
+    pub id: u64,
+    pub owner: Principal,
+    pub depositor: Principal,
+    pub amount: u64,
+    pub lock_period_days: u32,
+    pub label: Option<String>,
+    pub submitted_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Snapshot {
+    pub id: u64,
+    pub created_at: u64,
+    pub root: [u8; 32],
+    pub total_weight: u64,
+    pub leaves: Vec<(Principal, u64)>,  // (principal, voting weight), sorted by principal
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SnapshotInfo {
+    pub id: u64,
+    pub created_at: u64,
+    pub root: String,
+    pub total_weight: u64,
+    pub leaf_count: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ProofStep {
+    pub sibling: String,
+    pub sibling_is_left: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SnapshotProof {
+    pub snapshot_id: u64,
+    pub root: String,
+    pub principal: Principal,
+    pub weight: u64,
+    pub leaf_index: u64,
+    pub proof: Vec<ProofStep>,  // Ordered from the leaf up to the root
+}
+
+// Integrator interface v1: these types only ever gain `opt` fields, so existing
+// decoders keep working; breaking changes go into a new `v2_` interface
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct V1PoolInfo {
+    pub total_staked: u64,
+    pub total_stakers: u64,
+    pub total_stakes: u64,
+    pub lock_periods_days: Vec<u32>,
+    pub withdrawal_fee: u64,
+    pub paused: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct V1Stake {
+    pub id: u64,
+    pub amount: u64,
+    pub lock_period_days: u32,
+    pub stake_time: u64,
+    pub unlock_time: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct V1UserPosition {
+    pub account: Principal,  // Account owner, after resolving linked principals
+    pub total_staked: u64,
+    pub voting_power: u64,
+    pub stakes: Vec<V1Stake>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WebhookConfig {
+    pub url: String,
+    pub enabled: bool,  // Kill switch: when false nothing is queued or sent
+    pub large_withdrawal_threshold: u64,
+    pub cycles_low_threshold: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackgroundJob {
+    ScheduledWithdrawals,
+    RecurringDeposits,
+    FinalDistribution,
+    UnlockCallbacks,
+    CyclesCheck,
+    WebhookDelivery,
+}
+
+// A background job run that trapped, kept so operators can inspect and replay it
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct DeadLetter {
+    pub id: u64,
+    pub job: BackgroundJob,
+    pub error: String,
+    pub input_time: u64,  // current_time the run was given; replays reuse it
+    pub cursor: Option<String>,  // Job-specific progress marker at the time of failure
+    pub first_failed_at: u64,
+    pub last_failed_at: u64,
+    pub occurrences: u64,  // Repeats of the same job and error are folded into one entry
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WebhookEvent {
+    pub id: u64,
+    pub kind: String,
+    pub body: String,  // JSON payload
+    pub created_at: u64,
+    pub attempts: u32,
+    pub next_attempt_at: u64,
+}
+
+// Argument of the `on_unlock_notify` callback sent to canister stakers
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct UnlockNotice {
+    pub stake_id: u64,
+    pub amount: u64,
+    pub unlock_time: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Notification {
+    pub timestamp: u64,
+    pub message: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum HistoryKind {
+    Deposit,
+    Withdraw,
+    FundedDeposit { beneficiary: Principal },  // Recorded for the depositor of a deposit_for
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub timestamp: u64,
+    pub kind: HistoryKind,
+    pub stake_id: u64,
+    pub amount: u64,  // For withdrawals, what reached the destination
+    pub fee: u64,  // Transfer fee taken from the stake on top of `amount`
+    pub actor: Principal,  // Principal that made the call
+    pub destination: Option<Account>,  // Where a withdrawal was paid
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Statement {
+    pub from_ts: u64,
+    pub to_ts: u64,
+    pub deposits: u64,
+    pub withdrawals: u64,
+    pub funded_for_others: u64,  // Paid by the caller into other accounts' stakes
+    pub fees_paid: u64,
+    pub net_position_change: i64,  // deposits - withdrawals - fees_paid
+    pub entries: Vec<HistoryEntry>,
+}
+
+// Request and response shapes the HTTP gateway uses for `http_request`
+#[derive(CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StakeReceipt {
+    pub stake_id: u64,
+    pub amount: u64,
+    pub lock_tier: u32,  // lock_period_days
+    pub stake_time: u64,
+    pub unlock_at: u64,
+    pub seconds_remaining: u64,
+    pub account_id: AccountIdentifier,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum DepositResult {
+    Staked(StakeReceipt),
+    PendingApproval { pending_deposit_id: u64, amount: u64 },  // Held by the whale gate
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct WithdrawReceipt {
+    pub stake_id: u64,
+    pub amount: u64,
+    pub fee: u64,
+    pub payout: u64,
+    pub destination: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum WithdrawError {
+    Locked { stake_id: u64, unlock_at: u64, seconds_remaining: u64, lock_tier: u32 },
+    Rejected(String),
+}
+
+impl std::fmt::Display for WithdrawError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WithdrawError::Locked { stake_id, unlock_at, .. } => {
+                write!(f, "Stake {} is still locked until {}", stake_id, unlock_at)
+            }
+            WithdrawError::Rejected(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl From<String> for WithdrawError {
+    fn from(reason: String) -> Self {
+        WithdrawError::Rejected(reason)
+    }
+}
+
+impl From<WithdrawError> for String {
+    fn from(error: WithdrawError) -> Self {
+        error.to_string()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ReclaimError {
+    NotExpired { voucher_id: u64, expires_at: u64, seconds_remaining: u64 },
+    Rejected(String),
+}
+
+impl std::fmt::Display for ReclaimError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReclaimError::NotExpired { voucher_id, expires_at, .. } => {
+                write!(f, "Voucher {} can be reclaimed once it expires at {}", voucher_id, expires_at)
+            }
+            ReclaimError::Rejected(reason) => f.write_str(reason),
+        }
+    }
+}
+
+impl From<String> for ReclaimError {
+    fn from(reason: String) -> Self {
+        ReclaimError::Rejected(reason)
+    }
+}
+
+#[cfg(feature = "faucet")]
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum FaucetError {
+    CoolingDown { retry_at: u64, retry_after: u64 },
+    Rejected(String),
+}
+
+#[cfg(feature = "faucet")]
+impl std::fmt::Display for FaucetError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FaucetError::CoolingDown { retry_at, .. } => write!(f, "Faucet already used; try again at {}", retry_at),
+            FaucetError::Rejected(reason) => f.write_str(reason),
+        }
+    }
+}
+
+#[cfg(feature = "faucet")]
+impl From<String> for FaucetError {
+    fn from(reason: String) -> Self {
+        FaucetError::Rejected(reason)
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct DepositRequest {
+    pub amount: u64,
+    pub lock_period_days: u32,
+    pub label: Option<String>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct WithdrawRequest {
+    pub stake_index: usize,
+    pub destination: Option<Account>,  // Defaults to the caller's default account
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct UserPreferences {
+    pub notifications_opt_in: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LossEvent {
+    pub id: u64,
+    pub event_type: String,
+    pub product: String,
+    pub event_time: u64,
+    pub severity: u32,
+    pub attestation_hash: [u8; 32],
+    pub reporter: Principal,
+    pub recorded_at: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct LossEventRequest {
+    pub event_type: String,
+    pub product: String,
+    pub event_time: u64,
+    pub severity: u32,
+    pub attestation_hash: [u8; 32],
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PoolState {
+    pub stakes: Vec<(Principal, UserStakes)>,
+    pub total_pool_amount: u64,
+    pub next_subaccount_nonce: u64,
+    pub next_stake_id: u64,
+    pub authorized_principals: Vec<Principal>,
+    pub canister_balance: u64,
+    pub loss_reporters: Vec<Principal>,
+    pub loss_events: Vec<LossEvent>,
+    pub preferences: Vec<(Principal, UserPreferences)>,
+    pub trusted_origins: Vec<String>,
+    pub account_links: Vec<(Principal, Principal)>,  // (linked principal, account owner)
+    pub history: Vec<(Principal, Vec<HistoryEntry>)>,
+    pub operator_grants: Vec<((Principal, Principal), OperatorGrant)>,  // ((owner, operator), grant)
+    pub scheduled_withdrawals: Vec<ScheduledWithdrawal>,
+    pub recurring_deposits: Vec<RecurringDeposit>,
+    pub next_recurring_id: u64,
+    pub notifications: Vec<(Principal, Vec<Notification>)>,
+    pub snapshots: Vec<Snapshot>,
+    pub next_snapshot_id: u64,
+    pub webhook_config: Option<WebhookConfig>,
+    pub webhook_queue: Vec<WebhookEvent>,
+    pub lifecycle: PoolLifecycle,
+    pub distribution_in_progress: bool,
+    pub unlock_callbacks: Vec<(Principal, String)>,  // (canister account, method)
+    pub unlock_notify_cursor: (u64, u64),
+    pub scheduled_withdrawal_cursor: u64,
+    pub token_metadata: Option<TokenMetadata>,
+    pub lending_canisters: Vec<Principal>,
+    pub liens: Vec<Lien>,
+    pub vouchers: Vec<Voucher>,
+    pub activity: Vec<(u64, DayActivity)>,  // (day index, activity)
+    pub dead_letters: Vec<DeadLetter>,
+    pub next_dead_letter_id: u64,
+    pub next_voucher_id: u64,
+    pub whale_gate_threshold: Option<u64>,
+    pub concentration_limit: Option<ConcentrationLimit>,
+    pub pending_deposits: Vec<PendingDeposit>,
+    pub next_pending_deposit_id: u64,
+    #[cfg(feature = "faucet")]
+    pub faucet_balances: Vec<(Principal, u64)>,
+    #[cfg(feature = "faucet")]
+    pub faucet_last_drip: Vec<(Principal, u64)>,  // (account owner, drip time)
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountAudit {
+    pub owner: Principal,
+    pub stake_id: u64,
+    pub subaccount: Subaccount,
+    pub account_id: AccountIdentifier,
+    pub expected_balance: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct AuditPage {
+    pub page: u64,
+    pub total_pages: u64,
+    pub total_expected: u64,  // Across all pages; should match the pool total
+    pub accounts: Vec<AccountAudit>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct StateChunk {
+    pub export_id: u64,
+    pub snapshot_hash: String,  // SHA-256 of the whole encoded snapshot, for checking the reassembled bytes
+    pub chunk_index: u64,
+    pub total_chunks: u64,
+    pub data: Vec<u8>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Backup {
+    pub id: u64,
+    pub created_at: u64,
+    pub data: Vec<u8>,
+}
+
+// Stable memory layout: stakes are packed into fixed-size records instead of
+// candid records, labels are kept on the side since most stakes have none
+#[derive(CandidType, Deserialize)]
+pub struct StableState {
+    pub pool: PoolState,  // `stakes` is left empty
+    pub packed_stakes: Vec<(Principal, Vec<u8>)>,
+    pub stake_labels: Vec<(u64, String)>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct BackupInfo {
+    pub id: u64,
+    pub created_at: u64,
+    pub size_bytes: u64,
+}
+
+// ICRC-21 consent message types, field and variant names follow the standard
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ConsentMessageMetadata {
+    pub language: String,
+    pub utc_offset_minutes: Option<i16>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum DisplayMessageType {
+    GenericDisplay,
+    LineDisplay { characters_per_line: u16, lines_per_page: u16 },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ConsentMessageSpec {
+    pub metadata: ConsentMessageMetadata,
+    pub device_spec: Option<DisplayMessageType>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ConsentMessageRequest {
+    pub method: String,
+    pub arg: Vec<u8>,
+    pub user_preferences: ConsentMessageSpec,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LineDisplayPage {
+    pub lines: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ConsentMessage {
+    GenericDisplayMessage(String),
+    LineDisplayMessage { pages: Vec<LineDisplayPage> },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ConsentInfo {
+    pub consent_message: ConsentMessage,
+    pub metadata: ConsentMessageMetadata,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ErrorInfo {
+    pub description: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Icrc21Error {
+    UnsupportedCanisterCall(ErrorInfo),
+    ConsentMessageUnavailable(ErrorInfo),
+    InsufficientPayment(ErrorInfo),
+    GenericError { error_code: candid::Nat, description: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Icrc28TrustedOriginsResponse {
+    pub trusted_origins: Vec<String>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum Permission {
+    ReadOnly,
+    DepositOnly,                // Includes read access
+    WithdrawUpTo { limit: u64 },  // Includes read and deposit access; limit is cumulative, in base units
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct OperatorGrant {
+    pub permission: Permission,
+    pub withdrawn: u64,  // Total withdrawn under the current grant
+    pub granted_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LinkChallenge {
+    pub owner: Principal,
+    pub expires_at: u64,
+}
+
+thread_local! {
+    static STAKES: RefCell<BTreeMap<Principal, UserStakes>> = const { RefCell::new(BTreeMap::new()) };
+    static TOTAL_POOL_AMOUNT: RefCell<u64> = const { RefCell::new(0) };
+    static NEXT_SUBACCOUNT_NONCE: RefCell<u64> = const { RefCell::new(1) };
+    static NEXT_STAKE_ID: RefCell<u64> = const { RefCell::new(1) };
+    static AUTHORIZED_PRINCIPALS: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
+    static CANISTER_BALANCE: RefCell<u64> = const { RefCell::new(1_000_000_000_000) }; // Example balance for testing
+    #[cfg(feature = "faucet")]
+    static FAUCET_BALANCES: RefCell<BTreeMap<Principal, u64>> = const { RefCell::new(BTreeMap::new()) };  // Keyed by account owner
+    #[cfg(feature = "faucet")]
+    static FAUCET_LAST_DRIP: RefCell<BTreeMap<Principal, u64>> = const { RefCell::new(BTreeMap::new()) };
+    static LOSS_REPORTERS: RefCell<Vec<Principal>> = const { RefCell::new(Vec::new()) };
+    static LOSS_EVENTS: RefCell<Vec<LossEvent>> = const { RefCell::new(Vec::new()) };
+    static PAUSED: RefCell<bool> = const { RefCell::new(false) };
+    static BACKUPS: RefCell<Vec<Backup>> = const { RefCell::new(Vec::new()) };
+    static NEXT_BACKUP_ID: RefCell<u64> = const { RefCell::new(1) };
+    static STATE_HASH: RefCell<[u8; 32]> = const { RefCell::new([0u8; 32]) };
+    static STATE_EXPORT: RefCell<Option<(u64, String, Vec<u8>)>> = const { RefCell::new(None) };  // (export id, hash, encoded snapshot)
+    static TRUSTED_ORIGINS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    static ACCOUNT_LINKS: RefCell<BTreeMap<Principal, Principal>> = const { RefCell::new(BTreeMap::new()) };  // linked -> owner
+    static LINK_CHALLENGES: RefCell<BTreeMap<String, LinkChallenge>> = const { RefCell::new(BTreeMap::new()) };
+    static STATEMENT_TOKENS: RefCell<BTreeMap<String, (Principal, u64)>> = const { RefCell::new(BTreeMap::new()) };  // token -> (owner, expires_at)
+    static USER_HISTORY: RefCell<BTreeMap<Principal, Vec<HistoryEntry>>> = const { RefCell::new(BTreeMap::new()) };
+    static OPERATOR_GRANTS: RefCell<BTreeMap<(Principal, Principal), OperatorGrant>> = const { RefCell::new(BTreeMap::new()) };
+    static SCHEDULED_WITHDRAWALS: RefCell<BTreeMap<u64, ScheduledWithdrawal>> = const { RefCell::new(BTreeMap::new()) };  // keyed by stake id
+    static LAST_MATURITY_RUN: RefCell<u64> = const { RefCell::new(0) };
+    static LIFECYCLE: RefCell<PoolLifecycle> = const { RefCell::new(PoolLifecycle::Bootstrap) };
+    static DISTRIBUTION_IN_PROGRESS: RefCell<bool> = const { RefCell::new(false) };
+    static LENDING_CANISTERS: RefCell<BTreeSet<Principal>> = const { RefCell::new(BTreeSet::new()) };
+    static LIENS: RefCell<BTreeMap<u64, Lien>> = const { RefCell::new(BTreeMap::new()) };  // Keyed by stake id
+    static DEAD_LETTERS: RefCell<Vec<DeadLetter>> = const { RefCell::new(Vec::new()) };
+    static NEXT_DEAD_LETTER_ID: RefCell<u64> = const { RefCell::new(1) };
+    static JOBS_IN_FLIGHT: RefCell<bool> = const { RefCell::new(false) };
+    static ACTIVITY: RefCell<BTreeMap<u64, DayActivity>> = const { RefCell::new(BTreeMap::new()) };  // Keyed by day index
+    static VOUCHERS: RefCell<BTreeMap<String, Voucher>> = const { RefCell::new(BTreeMap::new()) };  // Keyed by code hash
+    static NEXT_VOUCHER_ID: RefCell<u64> = const { RefCell::new(1) };
+    static TOKEN_METADATA: RefCell<Option<TokenMetadata>> = const { RefCell::new(None) };
+    static UNLOCK_CALLBACKS: RefCell<BTreeMap<Principal, String>> = const { RefCell::new(BTreeMap::new()) };
+    static UNLOCK_NOTIFY_CURSOR: RefCell<(u64, u64)> = const { RefCell::new((0, 0)) };  // (unlock_time, stake_id) of the last notice sent
+    static SCHEDULED_WITHDRAWAL_CURSOR: RefCell<u64> = const { RefCell::new(0) };  // Stake id the next run starts from
+    static WHALE_GATE_THRESHOLD: RefCell<Option<u64>> = const { RefCell::new(None) };
+    static CONCENTRATION_LIMIT: RefCell<Option<ConcentrationLimit>> = const { RefCell::new(None) };
+    static PENDING_DEPOSITS: RefCell<BTreeMap<u64, PendingDeposit>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_PENDING_DEPOSIT_ID: RefCell<u64> = const { RefCell::new(1) };
+    static RECURRING_DEPOSITS: RefCell<BTreeMap<u64, RecurringDeposit>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_RECURRING_ID: RefCell<u64> = const { RefCell::new(1) };
+    static NOTIFICATIONS: RefCell<BTreeMap<Principal, Vec<Notification>>> = const { RefCell::new(BTreeMap::new()) };
+    static SNAPSHOTS: RefCell<Vec<Snapshot>> = const { RefCell::new(Vec::new()) };
+    static NEXT_SNAPSHOT_ID: RefCell<u64> = const { RefCell::new(1) };
+    static WEBHOOK_CONFIG: RefCell<Option<WebhookConfig>> = const { RefCell::new(None) };
+    static WEBHOOK_QUEUE: RefCell<Vec<WebhookEvent>> = const { RefCell::new(Vec::new()) };
+    static NEXT_WEBHOOK_EVENT_ID: RefCell<u64> = const { RefCell::new(1) };
+    static LAST_CYCLES_ALERT: RefCell<u64> = const { RefCell::new(0) };
+    static USER_PREFERENCES: RefCell<BTreeMap<Principal, UserPreferences>> = const { RefCell::new(BTreeMap::new()) };
+    
+    // Aggregate counters maintained on every stake mutation, rebuilt on restore
+    static STAKE_COUNT: RefCell<u64> = const { RefCell::new(0) };
+    static STATE_DIGEST: RefCell<(u128, u128)> = const { RefCell::new((0, 0)) };  // Sum of record digests, see record_digest
+    static TIER_COUNTS: RefCell<BTreeMap<u32, u64>> = const { RefCell::new(BTreeMap::new()) };
+    static PENDING_UNLOCKS: RefCell<BTreeMap<u64, u64>> = const { RefCell::new(BTreeMap::new()) };  // unlock_time -> count
+    static MATURED_COUNT: RefCell<u64> = const { RefCell::new(0) };
+    static MATURITY_CURSOR: RefCell<u64> = const { RefCell::new(0) };  // PENDING_UNLOCKS holds only times after this
+    static OPERATION_METRICS: RefCell<BTreeMap<Operation, OperationMetrics>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+#[init]
+fn init() {
+    let caller = ic_cdk::caller();
+    AUTHORIZED_PRINCIPALS.with(|auth| {
+        auth.borrow_mut().push(caller);
+    });
+    refresh_state_hash();
+}
+
+#[pre_upgrade]
+fn pre_upgrade() {
+    // Refuse to carry inconsistent state into the new version
+    let violations = check_invariants();
+    if !violations.is_empty() {
+        ic_cdk::trap(&format!("Upgrade aborted, invariants violated: {}", violations.join("; ")));
+    }
+    
+    let state = pack_state(snapshot_state());
+    let paused = PAUSED.with(|p| *p.borrow());
+    let backups = BACKUPS.with(|b| b.borrow().clone());
+    let next_backup_id = NEXT_BACKUP_ID.with(|n| *n.borrow());
+    
+    ic_cdk::storage::stable_save((state, paused, backups, next_backup_id))
+        .expect("Failed to save state to stable memory");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Versions before stable persistence left stable memory empty. Their pools had no
+    // lifecycle and were already open to withdrawals, so they come back active.
+    if ic_cdk::api::stable::stable_size() == 0 {
+        let caller = ic_cdk::caller();
+        AUTHORIZED_PRINCIPALS.with(|auth| {
+            auth.borrow_mut().push(caller);
+        });
+        LIFECYCLE.with(|stage| *stage.borrow_mut() = PoolLifecycle::Active);
+        refresh_state_hash();
+        return;
+    }
+    
+    let (state, paused, backups, next_backup_id): (StableState, bool, Vec<Backup>, u64) =
+        ic_cdk::storage::stable_restore().expect("Failed to restore state from stable memory");
+    
+    restore_state(unpack_state(state).expect("Corrupted stake records in stable memory"));
+    PAUSED.with(|p| *p.borrow_mut() = paused);
+    BACKUPS.with(|b| *b.borrow_mut() = backups);
+    NEXT_BACKUP_ID.with(|n| *n.borrow_mut() = next_backup_id);
+    
+    let violations = check_invariants();
+    if violations.is_empty() {
+        ic_cdk::println!("Post-upgrade invariant check passed");
+    } else {
+        ic_cdk::println!("Post-upgrade invariant check failed: {}", violations.join("; "));
+    }
+}
+
+// Returns a description of every violated accounting invariant, empty if the state is consistent
+fn check_invariants() -> Vec<String> {
+    let mut violations = Vec::new();
+    let mut pool_sum: u128 = 0;
+    let mut stake_count: u64 = 0;
+    let mut seen_ids = BTreeSet::new();
+    let next_stake_id = NEXT_STAKE_ID.with(|n| *n.borrow());
+    
+    STAKES.with(|stakes| {
+        for (principal, user_stakes) in stakes.borrow().iter() {
+            let user_sum: u128 = user_stakes.stakes.iter().map(|stake| stake.amount as u128).sum();
+            if user_sum != user_stakes.total_staked as u128 {
+                violations.push(format!(
+                    "{} total_staked {} != sum of stakes {}", 
+                    principal, user_stakes.total_staked, user_sum
+                ));
+            }
+            if !user_stakes.stakes.windows(2).all(|pair| pair[0].id < pair[1].id) {
+                violations.push(format!("{} stakes are not ordered by id", principal));
+            }
+            for stake in &user_stakes.stakes {
+                if stake.id >= next_stake_id || !seen_ids.insert(stake.id) {
+                    violations.push(format!("Stake id {} is duplicated or not yet issued", stake.id));
+                }
+            }
+            pool_sum += user_sum;
+            stake_count += user_stakes.stakes.len() as u64;
+        }
+    });
+    
+    let total_pool_amount = TOTAL_POOL_AMOUNT.with(|total| *total.borrow());
+    if pool_sum != total_pool_amount as u128 {
+        violations.push(format!("TOTAL_POOL_AMOUNT {} != sum of stakes {}", total_pool_amount, pool_sum));
+    }
+    
+    let counted = STAKE_COUNT.with(|count| *count.borrow());
+    if counted != stake_count {
+        violations.push(format!("STAKE_COUNT {} != number of stakes {}", counted, stake_count));
+    }
+    
+    LIENS.with(|liens| {
+        for lien in liens.borrow().values() {
+            let stake_amount = find_stake_index(&lien.owner, lien.stake_id).and_then(|index| {
+                STAKES.with(|stakes| stakes.borrow().get(&lien.owner).map(|user| user.stakes[index].amount))
+            });
+            match stake_amount {
+                Some(amount) if lien.amount <= amount => {}
+                Some(amount) => violations.push(format!(
+                    "Lien on stake {} is {} but the stake holds {}", lien.stake_id, lien.amount, amount
+                )),
+                None => violations.push(format!("Lien on stake {} has no matching stake", lien.stake_id)),
+            }
+        }
+    });
+    
+    ACCOUNT_LINKS.with(|links| {
+        let links = links.borrow();
+        for (linked, owner) in links.iter() {
+            if links.contains_key(owner) {
+                violations.push(format!("{} is linked to {} which is itself linked", linked, owner));
+            }
+        }
+    });
+    
+    violations
+}
+
+#[heartbeat]
+fn heartbeat() {
+    let current_time = get_current_time();
+    let last_run = LAST_MATURITY_RUN.with(|last| *last.borrow());
+    if current_time < last_run + MATURITY_JOB_INTERVAL_SECONDS {
+        return;
+    }
+    LAST_MATURITY_RUN.with(|last| *last.borrow_mut() = current_time);
+    
+    if !JOBS_IN_FLIGHT.with(|f| *f.borrow()) {
+        ic_cdk::spawn(run_background_jobs(current_time));
+    }
+}
+
+// Each job runs in its own self-call, so a trap rolls back only that job and is recorded
+// instead of aborting the whole heartbeat on every run
+async fn run_background_jobs(current_time: u64) {
+    JOBS_IN_FLIGHT.with(|f| *f.borrow_mut() = true);
+    for job in [
+        BackgroundJob::ScheduledWithdrawals,
+        BackgroundJob::RecurringDeposits,
+        BackgroundJob::FinalDistribution,
+        BackgroundJob::UnlockCallbacks,
+        BackgroundJob::CyclesCheck,
+        BackgroundJob::WebhookDelivery,  // Last, so alerts queued by this run go out with it
+    ] {
+        if let Err(error) = call_background_job(job, current_time).await {
+            record_dead_letter(job, error, current_time);
+        }
+    }
+    JOBS_IN_FLIGHT.with(|f| *f.borrow_mut() = false);
+}
+
+async fn call_background_job(job: BackgroundJob, current_time: u64) -> Result<(), String> {
+    let result: ic_cdk::api::call::CallResult<()> =
+        ic_cdk::call(ic_cdk::id(), "run_background_job", (job, current_time)).await;
+    result.map_err(|(code, msg)| format!("{:?}: {}", code, msg))
+}
+
+#[update]
+async fn run_background_job(job: BackgroundJob, current_time: u64) {
+    if ic_cdk::caller() != ic_cdk::id() {
+        ic_cdk::trap("run_background_job can only be called by the canister itself");
+    }
+    
+    match job {
+        BackgroundJob::ScheduledWithdrawals => process_scheduled_withdrawals(current_time),
+        BackgroundJob::RecurringDeposits => process_recurring_deposits(current_time),
+        BackgroundJob::FinalDistribution => process_final_distribution(),
+        BackgroundJob::UnlockCallbacks => process_unlock_callbacks(current_time),
+        BackgroundJob::CyclesCheck => check_cycles_balance(current_time),
+        BackgroundJob::WebhookDelivery => deliver_next_webhook(current_time).await,
+    }
+}
+
+fn job_cursor(job: BackgroundJob) -> Option<String> {
+    match job {
+        BackgroundJob::UnlockCallbacks => {
+            let (unlock_time, stake_id) = UNLOCK_NOTIFY_CURSOR.with(|cursor| *cursor.borrow());
+            Some(format!("unlock_time={} stake_id={}", unlock_time, stake_id))
+        }
+        BackgroundJob::ScheduledWithdrawals => {
+            Some(format!("stake_id={}", SCHEDULED_WITHDRAWAL_CURSOR.with(|cursor| *cursor.borrow())))
+        }
+        BackgroundJob::FinalDistribution => {
+            Some(format!("stakes_remaining={}", STAKE_COUNT.with(|count| *count.borrow())))
+        }
+        _ => None,
+    }
+}
+
+fn record_dead_letter(job: BackgroundJob, error: String, input_time: u64) {
+    ic_cdk::println!("Background job {:?} failed: {}", job, error);
+    let now = get_current_time();
+    let cursor = job_cursor(job);
+    
+    DEAD_LETTERS.with(|letters| {
+        let mut letters = letters.borrow_mut();
+        if let Some(letter) = letters.iter_mut().find(|l| l.job == job && l.error == error) {
+            letter.occurrences += 1;
+            letter.last_failed_at = now;
+            letter.input_time = input_time;
+            letter.cursor = cursor;
+            return;
+        }
+        
+        if letters.len() >= MAX_DEAD_LETTERS {
+            letters.remove(0);
+        }
+        let id = NEXT_DEAD_LETTER_ID.with(|n| {
+            let current = *n.borrow();
+            *n.borrow_mut() = current + 1;
+            current
+        });
+        letters.push(DeadLetter {
+            id,
+            job,
+            error,
+            input_time,
+            cursor,
+            first_failed_at: now,
+            last_failed_at: now,
+            occurrences: 1,
+        });
+    });
+}
+
+#[query]
+fn get_dead_letters() -> Result<Vec<DeadLetter>, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    Ok(DEAD_LETTERS.with(|letters| letters.borrow().clone()))
+}
+
+// Runs the job again with its recorded input; the entry is removed once a run succeeds
+#[update]
+async fn replay_dead_letter(id: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    let letter = DEAD_LETTERS.with(|letters| letters.borrow().iter().find(|l| l.id == id).cloned())
+        .ok_or("Dead letter not found".to_string())?;
+    
+    match call_background_job(letter.job, letter.input_time).await {
+        Ok(()) => {
+            DEAD_LETTERS.with(|letters| letters.borrow_mut().retain(|l| l.id != id));
+            Ok(format!("Replayed {:?} successfully", letter.job))
+        }
+        Err(error) => {
+            DEAD_LETTERS.with(|letters| {
+                if let Some(letter) = letters.borrow_mut().iter_mut().find(|l| l.id == id) {
+                    letter.occurrences += 1;
+                    letter.last_failed_at = get_current_time();
+                    letter.error = error.clone();
+                }
+            });
+            Err(format!("Replay failed: {}", error))
+        }
+    }
+}
+
+fn check_cycles_balance(current_time: u64) {
+    let Some(threshold) = WEBHOOK_CONFIG.with(|config| config.borrow().as_ref().map(|c| c.cycles_low_threshold)) else {
+        return;
+    };
+    let last_alert = LAST_CYCLES_ALERT.with(|last| *last.borrow());
+    let cycles = ic_cdk::api::canister_balance();
+    
+    if cycles < threshold && current_time >= last_alert + CYCLES_ALERT_INTERVAL_SECONDS {
+        LAST_CYCLES_ALERT.with(|last| *last.borrow_mut() = current_time);
+        enqueue_alert("cycles_low", format!("{{\"cycles\":{},\"threshold\":{}}}", cycles, threshold));
+    }
+}
+
+// Pulls and stakes due recurring deposits, pausing plans whose allowance is used up
+fn process_recurring_deposits(current_time: u64) {
+    let due: Vec<RecurringDeposit> = RECURRING_DEPOSITS.with(|plans| {
+        plans.borrow()
+            .values()
+            .filter(|plan| plan.status == RecurringStatus::Active && plan.next_run_at <= current_time)
+            .take(MAX_JOBS_PER_RUN)
+            .cloned()
+            .collect()
+    });
+    
+    for plan in due {
+        if plan.allowance_remaining < plan.amount {
+            let reason = format!(
+                "Allowance exhausted: {} left, {} needed", 
+                format_amount(plan.allowance_remaining), format_amount(plan.amount)
+            );
+            notify(plan.owner, format!("Recurring deposit {} paused. {}", plan.id, reason));
+            RECURRING_DEPOSITS.with(|plans| {
+                if let Some(plan) = plans.borrow_mut().get_mut(&plan.id) {
+                    plan.status = RecurringStatus::Paused { reason };
+                }
+            });
+            continue;
+        }
+        
+        let request = DepositRequest {
+            amount: plan.amount,
+            lock_period_days: plan.lock_period_days,
+            label: Some(format!("Recurring deposit {}", plan.id)),
+        };
+        let result = create_stake(plan.owner, plan.owner, request);
+        
+        if let Err(e) = &result {
+            notify(plan.owner, format!("Recurring deposit {} failed: {}", plan.id, e));
+        }
+        
+        RECURRING_DEPOSITS.with(|plans| {
+            if let Some(plan) = plans.borrow_mut().get_mut(&plan.id) {
+                if result.is_ok() {
+                    plan.allowance_remaining -= plan.amount;
+                    plan.deposits_made += 1;
+                }
+                // After downtime the plan resumes its cadence instead of firing once per missed interval
+                plan.next_run_at = (plan.next_run_at + plan.interval_seconds).max(current_time + plan.interval_seconds);
+            }
+        });
+    }
+}
+
+// Pays out scheduled withdrawals whose stakes have unlocked, at most MAX_JOBS_PER_RUN per call
+fn process_scheduled_withdrawals(current_time: u64) {
+    // Each run resumes after the last entry tried, so entries that keep failing cannot starve the rest
+    let cursor = SCHEDULED_WITHDRAWAL_CURSOR.with(|cursor| *cursor.borrow());
+    let due: Vec<ScheduledWithdrawal> = SCHEDULED_WITHDRAWALS.with(|scheduled| {
+        let scheduled = scheduled.borrow();
+        scheduled.range(cursor..)
+            .chain(scheduled.range(..cursor))
+            .map(|(_, entry)| entry)
+            .filter(|entry| {
+                STAKES.with(|stakes| {
+                    stakes.borrow()
+                        .get(&entry.owner)
+                        .and_then(|user_stakes| user_stakes.stakes.iter().find(|stake| stake.id == entry.stake_id))
+                        .is_none_or(|stake| stake.unlock_time <= current_time)
+                })
+            })
+            .take(MAX_JOBS_PER_RUN)
+            .cloned()
+            .collect()
+    });
+    if let Some(last) = due.last() {
+        SCHEDULED_WITHDRAWAL_CURSOR.with(|cursor| *cursor.borrow_mut() = last.stake_id + 1);
+    }
+    
+    for entry in due {
+        let Some(stake_index) = find_stake_index(&entry.owner, entry.stake_id) else {
+            // Stake is gone, e.g. withdrawn manually
+            SCHEDULED_WITHDRAWALS.with(|scheduled| scheduled.borrow_mut().remove(&entry.stake_id));
+            continue;
+        };
+        
+        // On failure (e.g. paused pool) the entry stays scheduled and is retried next run
+        if let Err(e) = withdraw_stake(entry.owner, entry.scheduled_by, stake_index, entry.destination.clone()) {
+            let error = e.to_string();
+            ic_cdk::println!("Scheduled withdrawal of stake {} failed: {}", entry.stake_id, error);
+            if entry.last_error.as_ref() == Some(&error) {
+                continue;
+            }
+            
+            let backlog = SCHEDULED_WITHDRAWALS.with(|scheduled| {
+                let mut scheduled = scheduled.borrow_mut();
+                if let Some(stored) = scheduled.get_mut(&entry.stake_id) {
+                    stored.last_error = Some(error.clone());
+                }
+                scheduled.len()
+            });
+            enqueue_alert("failed_transfer", format!(
+                "{{\"stake_id\":{},\"error\":\"{}\",\"backlog\":{}}}", 
+                entry.stake_id, json_escape(&error), backlog
+            ));
+        }
+    }
+}
+
+fn get_current_time() -> u64 {
+    ic_cdk::api::time() / 1_000_000_000  // Convert from nanoseconds to seconds
+}
+
+fn is_authorized(caller: &Principal) -> bool {
+    AUTHORIZED_PRINCIPALS.with(|auth| {
+        auth.borrow().contains(caller)
+    })
+}
+
+// Call right before a successful return; the counter covers the whole message so far
+fn record_instructions(operation: Operation) {
+    let instructions = ic_cdk::api::instruction_counter();
+    OPERATION_METRICS.with(|metrics| {
+        let mut metrics = metrics.borrow_mut();
+        let entry = metrics.entry(operation).or_default();
+        entry.calls += 1;
+        entry.last_instructions = instructions;
+        entry.max_instructions = entry.max_instructions.max(instructions);
+        entry.total_instructions += instructions;
+    });
+}
+
+// Principal whose stakes the given principal manages: its own, or the account it is linked to
+fn account_owner(principal: Principal) -> Principal {
+    ACCOUNT_LINKS.with(|links| {
+        links.borrow().get(&principal).copied().unwrap_or(principal)
+    })
+}
+
+fn record_history(owner: Principal, kind: HistoryKind, stake_id: u64, amount: u64, fee: u64, actor: Principal, destination: Option<Account>) {
+    record_activity(owner, &kind, amount);
+    USER_HISTORY.with(|history| {
+        history.borrow_mut().entry(owner).or_default().push(HistoryEntry {
+            timestamp: get_current_time(),
+            kind,
+            stake_id,
+            amount,
+            fee,
+            actor,
+            destination,
+        });
+    });
+}
+
+fn record_activity(owner: Principal, kind: &HistoryKind, amount: u64) {
+    let today = get_current_time() / 86_400;
+    ACTIVITY.with(|activity| {
+        let mut activity = activity.borrow_mut();
+        // Drop days that have left the window
+        while activity.first_key_value().is_some_and(|(day, _)| *day + ACTIVITY_WINDOW_DAYS <= today) {
+            activity.pop_first();
+        }
+        
+        let day = activity.entry(today).or_default();
+        match kind {
+            HistoryKind::Deposit => {
+                day.deposit_volume += amount;
+                day.deposit_count += 1;
+                day.flows.entry(owner).or_default().0 += amount;
+            }
+            HistoryKind::Withdraw => {
+                day.withdraw_volume += amount;
+                day.withdraw_count += 1;
+                day.flows.entry(owner).or_default().1 += amount;
+            }
+            HistoryKind::FundedDeposit { .. } => {}  // Already counted as the beneficiary's deposit
+        }
+    });
+}
+
+// Normalizes an all-zero subaccount to None, the ICRC-1 default account
+fn validate_account(account: Account) -> Result<Account, String> {
+    if account.owner == Principal::anonymous() {
+        return Err("Destination owner cannot be the anonymous principal".to_string());
+    }
+    if account.owner == Principal::management_canister() {
+        return Err("Destination owner cannot be the management canister".to_string());
+    }
+    if account.owner.as_slice().len() > 29 {
+        return Err("Destination owner is not a valid principal".to_string());
+    }
+    
+    let subaccount = account.subaccount.filter(|subaccount| *subaccount != [0u8; 32]);
+    Ok(Account { owner: account.owner, subaccount })
+}
+
+fn format_account(account: &Account) -> String {
+    match &account.subaccount {
+        Some(subaccount) => format!("{} (subaccount {})", account.owner, hex::encode(subaccount)),
+        None => account.owner.to_string(),
+    }
+}
+
+// Only delivered to users who opted in to notifications
+fn notify(owner: Principal, message: String) {
+    let opted_in = USER_PREFERENCES.with(|prefs| {
+        prefs.borrow().get(&owner).is_some_and(|p| p.notifications_opt_in)
+    });
+    if !opted_in {
+        return;
+    }
+    
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        let inbox = notifications.entry(owner).or_default();
+        if inbox.len() >= MAX_NOTIFICATIONS {
+            inbox.remove(0);
+        }
+        inbox.push(Notification {
+            timestamp: get_current_time(),
+            message,
+        });
+    });
+}
+
+fn find_stake_index(owner: &Principal, stake_id: u64) -> Option<usize> {
+    STAKES.with(|stakes| {
+        stakes.borrow()
+            .get(owner)
+            .and_then(|user_stakes| user_stakes.stakes.iter().position(|stake| stake.id == stake_id))
+    })
+}
+
+fn operator_grant(owner: &Principal, operator: &Principal) -> Result<OperatorGrant, String> {
+    OPERATOR_GRANTS.with(|grants| grants.borrow().get(&(*owner, *operator)).cloned())
+        .ok_or("Unauthorized".to_string())
+}
+
+fn lifecycle() -> PoolLifecycle {
+    LIFECYCLE.with(|lifecycle| *lifecycle.borrow())
+}
+
+fn is_paused() -> bool {
+    PAUSED.with(|p| *p.borrow())
+}
+
+fn is_loss_reporter(caller: &Principal) -> bool {
+    LOSS_REPORTERS.with(|reporters| {
+        reporters.borrow().contains(caller)
+    })
+}
+
+fn generate_subaccount(caller: &Principal) -> Subaccount {
+    let nonce = NEXT_SUBACCOUNT_NONCE.with(|n| {
+        let current = *n.borrow();
+        *n.borrow_mut() = current + 1;
+        current
+    });
+    
+    let mut hasher = Sha256::new();
+    hasher.update(caller.as_slice());
+    hasher.update(nonce.to_be_bytes());
+    hasher.update(b"staking_pool");  // Add a unique identifier to the hash
+    
+    let hash = hasher.finalize();
+    let mut subaccount = [0u8; 32];
+    subaccount.copy_from_slice(&hash[..32]);  // Only take the first 32 bytes
+    
+    subaccount
+}
+
+// Fixed-width big-endian encoding so the hash does not depend on candid encoding
+fn compute_state_hash() -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(TOTAL_POOL_AMOUNT.with(|total| *total.borrow()).to_be_bytes());
+    hasher.update(CANISTER_BALANCE.with(|balance| *balance.borrow()).to_be_bytes());
+    let (high, low) = STATE_DIGEST.with(|digest| *digest.borrow());
+    hasher.update(high.to_be_bytes());
+    hasher.update(low.to_be_bytes());
+    hasher.finalize().into()
+}
+
+// Records are combined by addition, which is order-independent, so a mutation only adds or
+// subtracts the digests of the records it touches instead of rehashing the whole state
+fn record_digest(tag: u8, write: impl FnOnce(&mut Sha256)) -> (u128, u128) {
+    let mut hasher = Sha256::new();
+    hasher.update([tag]);
+    write(&mut hasher);
+    let digest: [u8; 32] = hasher.finalize().into();
+    (
+        u128::from_be_bytes(digest[..16].try_into().unwrap()),
+        u128::from_be_bytes(digest[16..].try_into().unwrap()),
+    )
+}
+
+fn add_digest(record: (u128, u128)) {
+    STATE_DIGEST.with(|digest| {
+        let mut digest = digest.borrow_mut();
+        *digest = (digest.0.wrapping_add(record.0), digest.1.wrapping_add(record.1));
+    });
+}
+
+fn remove_digest(record: (u128, u128)) {
+    STATE_DIGEST.with(|digest| {
+        let mut digest = digest.borrow_mut();
+        *digest = (digest.0.wrapping_sub(record.0), digest.1.wrapping_sub(record.1));
+    });
+}
+
+fn stake_digest(owner: &Principal, stake: &StakeInfo) -> (u128, u128) {
+    record_digest(0x00, |hasher| {
+        hash_principal(hasher, owner);
+        hasher.update(stake.id.to_be_bytes());
+        hasher.update(stake.amount.to_be_bytes());
+        hasher.update(stake.lock_period_days.to_be_bytes());
+        hasher.update(stake.stake_time.to_be_bytes());
+        hasher.update(stake.unlock_time.to_be_bytes());
+        hasher.update(stake.subaccount);
+    })
+}
+
+// Deposits held by the whale gate are funds the pool owes back or will stake
+fn pending_deposit_digest(deposit: &PendingDeposit) -> (u128, u128) {
+    record_digest(0x01, |hasher| {
+        hasher.update(deposit.id.to_be_bytes());
+        hash_principal(hasher, &deposit.owner);
+        hash_principal(hasher, &deposit.depositor);
+        hasher.update(deposit.amount.to_be_bytes());
+        hasher.update(deposit.lock_period_days.to_be_bytes());
+    })
+}
+
+// A lien moves part of a stake to its holder on settlement
+fn lien_digest(lien: &Lien) -> (u128, u128) {
+    record_digest(0x02, |hasher| {
+        hasher.update(lien.stake_id.to_be_bytes());
+        hash_principal(hasher, &lien.owner);
+        hash_principal(hasher, &lien.lien_holder);
+        hasher.update(lien.amount.to_be_bytes());
+    })
+}
+
+// Voucher escrow is funded but not yet staked
+fn voucher_digest(voucher: &Voucher) -> (u128, u128) {
+    record_digest(0x03, |hasher| {
+        hasher.update(voucher.id.to_be_bytes());
+        hash_principal(hasher, &voucher.sender);
+        hasher.update(voucher.amount.to_be_bytes());
+        hasher.update(voucher.lock_period_days.to_be_bytes());
+        hasher.update(voucher.code_hash.as_bytes());
+    })
+}
+
+// Simulated faucet balances stand in for ledger funds in test deployments
+#[cfg(feature = "faucet")]
+fn faucet_balance_digest(owner: &Principal, balance: u64) -> (u128, u128) {
+    record_digest(0x04, |hasher| {
+        hash_principal(hasher, owner);
+        hasher.update(balance.to_be_bytes());
+    })
+}
+
+#[cfg(feature = "faucet")]
+fn faucet_drip_digest(owner: &Principal, time: u64) -> (u128, u128) {
+    record_digest(0x05, |hasher| {
+        hash_principal(hasher, owner);
+        hasher.update(time.to_be_bytes());
+    })
+}
+
+#[cfg(feature = "faucet")]
+fn set_faucet_balance(owner: Principal, balance: u64) {
+    FAUCET_BALANCES.with(|balances| {
+        let mut balances = balances.borrow_mut();
+        if let Some(previous) = balances.insert(owner, balance) {
+            remove_digest(faucet_balance_digest(&owner, previous));
+        }
+        add_digest(faucet_balance_digest(&owner, balance));
+    });
+}
+
+#[cfg(feature = "faucet")]
+fn set_faucet_last_drip(owner: Principal, time: u64) {
+    FAUCET_LAST_DRIP.with(|drips| {
+        let mut drips = drips.borrow_mut();
+        if let Some(previous) = drips.insert(owner, time) {
+            remove_digest(faucet_drip_digest(&owner, previous));
+        }
+        add_digest(faucet_drip_digest(&owner, time));
+    });
+}
+
+// The maps below are only changed through these helpers, which keep STATE_DIGEST in step
+fn insert_pending_deposit(deposit: PendingDeposit) {
+    add_digest(pending_deposit_digest(&deposit));
+    if let Some(replaced) = PENDING_DEPOSITS.with(|pending| pending.borrow_mut().insert(deposit.id, deposit)) {
+        remove_digest(pending_deposit_digest(&replaced));
+    }
+}
+
+fn remove_pending_deposit(id: u64) -> Option<PendingDeposit> {
+    let removed = PENDING_DEPOSITS.with(|pending| pending.borrow_mut().remove(&id));
+    if let Some(deposit) = &removed {
+        remove_digest(pending_deposit_digest(deposit));
+    }
+    removed
+}
+
+fn insert_lien(lien: Lien) {
+    add_digest(lien_digest(&lien));
+    if let Some(replaced) = LIENS.with(|liens| liens.borrow_mut().insert(lien.stake_id, lien)) {
+        remove_digest(lien_digest(&replaced));
+    }
+}
+
+fn remove_lien(stake_id: u64) -> Option<Lien> {
+    let removed = LIENS.with(|liens| liens.borrow_mut().remove(&stake_id));
+    if let Some(lien) = &removed {
+        remove_digest(lien_digest(lien));
+    }
+    removed
+}
+
+fn insert_voucher(voucher: Voucher) {
+    add_digest(voucher_digest(&voucher));
+    if let Some(replaced) = VOUCHERS.with(|vouchers| vouchers.borrow_mut().insert(voucher.code_hash.clone(), voucher)) {
+        remove_digest(voucher_digest(&replaced));
+    }
+}
+
+fn remove_voucher(code_hash: &str) -> Option<Voucher> {
+    let removed = VOUCHERS.with(|vouchers| vouchers.borrow_mut().remove(code_hash));
+    if let Some(voucher) = &removed {
+        remove_digest(voucher_digest(voucher));
+    }
+    removed
+}
+
+fn hash_principal(hasher: &mut Sha256, principal: &Principal) {
+    hasher.update([principal.as_slice().len() as u8]);
+    hasher.update(principal.as_slice());
+}
+
+fn refresh_state_hash() {
+    let hash = compute_state_hash();
+    STATE_HASH.with(|h| *h.borrow_mut() = hash);
+}
+
+// Moves stakes that unlocked since the last call from PENDING_UNLOCKS into MATURED_COUNT
+fn advance_maturity(current_time: u64) {
+    let cursor = MATURITY_CURSOR.with(|c| *c.borrow());
+    if current_time <= cursor {
+        return;
+    }
+    
+    let matured: u64 = PENDING_UNLOCKS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        let still_locked = pending.split_off(&(current_time + 1));
+        let matured = pending.values().sum();
+        *pending = still_locked;
+        matured
+    });
+    
+    MATURED_COUNT.with(|count| *count.borrow_mut() += matured);
+    MATURITY_CURSOR.with(|c| *c.borrow_mut() = current_time);
+}
+
+fn record_stake_added(owner: &Principal, stake: &StakeInfo) {
+    STAKE_COUNT.with(|count| *count.borrow_mut() += 1);
+    add_digest(stake_digest(owner, stake));
+    TIER_COUNTS.with(|tiers| *tiers.borrow_mut().entry(stake.lock_period_days).or_insert(0) += 1);
+    
+    if stake.unlock_time <= MATURITY_CURSOR.with(|c| *c.borrow()) {
+        MATURED_COUNT.with(|count| *count.borrow_mut() += 1);
+    } else {
+        PENDING_UNLOCKS.with(|pending| *pending.borrow_mut().entry(stake.unlock_time).or_insert(0) += 1);
+    }
+}
+
+fn record_stake_removed(owner: &Principal, stake: &StakeInfo) {
+    STAKE_COUNT.with(|count| *count.borrow_mut() -= 1);
+    remove_digest(stake_digest(owner, stake));
+    TIER_COUNTS.with(|tiers| {
+        let mut tiers = tiers.borrow_mut();
+        if let Some(count) = tiers.get_mut(&stake.lock_period_days) {
+            *count -= 1;
+            if *count == 0 {
+                tiers.remove(&stake.lock_period_days);
+            }
+        }
+    });
+    
+    if stake.unlock_time <= MATURITY_CURSOR.with(|c| *c.borrow()) {
+        MATURED_COUNT.with(|count| *count.borrow_mut() -= 1);
+    } else {
+        PENDING_UNLOCKS.with(|pending| {
+            let mut pending = pending.borrow_mut();
+            if let Some(count) = pending.get_mut(&stake.unlock_time) {
+                *count -= 1;
+                if *count == 0 {
+                    pending.remove(&stake.unlock_time);
+                }
+            }
+        });
+    }
+}
+
+fn rebuild_stats() {
+    STAKE_COUNT.with(|count| *count.borrow_mut() = 0);
+    TIER_COUNTS.with(|tiers| tiers.borrow_mut().clear());
+    PENDING_UNLOCKS.with(|pending| pending.borrow_mut().clear());
+    MATURED_COUNT.with(|count| *count.borrow_mut() = 0);
+    MATURITY_CURSOR.with(|c| *c.borrow_mut() = 0);
+    STATE_DIGEST.with(|digest| *digest.borrow_mut() = (0, 0));
+    
+    STAKES.with(|stakes| {
+        for (owner, user_stakes) in stakes.borrow().iter() {
+            for stake in &user_stakes.stakes {
+                record_stake_added(owner, stake);
+            }
+        }
+    });
+    PENDING_DEPOSITS.with(|pending| pending.borrow().values().for_each(|deposit| add_digest(pending_deposit_digest(deposit))));
+    LIENS.with(|liens| liens.borrow().values().for_each(|lien| add_digest(lien_digest(lien))));
+    VOUCHERS.with(|vouchers| vouchers.borrow().values().for_each(|voucher| add_digest(voucher_digest(voucher))));
+    #[cfg(feature = "faucet")]
+    FAUCET_BALANCES.with(|balances| {
+        balances.borrow().iter().for_each(|(owner, balance)| add_digest(faucet_balance_digest(owner, *balance)))
+    });
+    #[cfg(feature = "faucet")]
+    FAUCET_LAST_DRIP.with(|drips| drips.borrow().iter().for_each(|(owner, time)| add_digest(faucet_drip_digest(owner, *time))));
+    
+    advance_maturity(get_current_time());
+}
+
+fn validate_label(label: &str) -> Result<(), String> {
+    if label.chars().count() > MAX_LABEL_LEN {
+        return Err(format!("Label must be at most {} characters", MAX_LABEL_LEN));
+    }
+    Ok(())
+}
+
+fn next_stake_id() -> u64 {
+    NEXT_STAKE_ID.with(|n| {
+        let current = *n.borrow();
+        *n.borrow_mut() = current + 1;
+        current
+    })
+}
+
+fn encode_stake(stake: &StakeInfo) -> [u8; STAKE_RECORD_SIZE] {
+    let mut record = [0u8; STAKE_RECORD_SIZE];
+    record[0..8].copy_from_slice(&stake.id.to_be_bytes());
+    record[8..16].copy_from_slice(&stake.amount.to_be_bytes());
+    record[16..20].copy_from_slice(&stake.lock_period_days.to_be_bytes());
+    record[20..28].copy_from_slice(&stake.stake_time.to_be_bytes());
+    record[28..36].copy_from_slice(&stake.unlock_time.to_be_bytes());
+    record[36..68].copy_from_slice(&stake.subaccount);
+    record
+}
+
+fn decode_stake(record: &[u8]) -> StakeInfo {
+    let u64_at = |start: usize| u64::from_be_bytes(record[start..start + 8].try_into().unwrap());
+    let mut subaccount = [0u8; 32];
+    subaccount.copy_from_slice(&record[36..68]);
+    
+    StakeInfo {
+        id: u64_at(0),
+        amount: u64_at(8),
+        lock_period_days: u32::from_be_bytes(record[16..20].try_into().unwrap()),
+        stake_time: u64_at(20),
+        unlock_time: u64_at(28),
+        subaccount,
+        label: None,
+    }
+}
+
+fn pack_state(mut pool: PoolState) -> StableState {
+    let mut packed_stakes = Vec::with_capacity(pool.stakes.len());
+    let mut stake_labels = Vec::new();
+    
+    for (principal, user_stakes) in std::mem::take(&mut pool.stakes) {
+        let mut packed = Vec::with_capacity(user_stakes.stakes.len() * STAKE_RECORD_SIZE);
+        for stake in &user_stakes.stakes {
+            packed.extend_from_slice(&encode_stake(stake));
+            if let Some(label) = &stake.label {
+                stake_labels.push((stake.id, label.clone()));
+            }
+        }
+        packed_stakes.push((principal, packed));
+    }
+    
+    StableState { pool, packed_stakes, stake_labels }
+}
+
+fn unpack_state(state: StableState) -> Result<PoolState, String> {
+    let mut labels: BTreeMap<u64, String> = state.stake_labels.into_iter().collect();
+    let mut pool = state.pool;
+    
+    for (principal, packed) in state.packed_stakes {
+        if packed.len() % STAKE_RECORD_SIZE != 0 {
+            return Err(format!("Stake records for {} have invalid length {}", principal, packed.len()));
+        }
+        
+        let stakes: Vec<StakeInfo> = packed
+            .chunks_exact(STAKE_RECORD_SIZE)
+            .map(|record| {
+                let mut stake = decode_stake(record);
+                stake.label = labels.remove(&stake.id);
+                stake
+            })
+            .collect();
+        let total_staked = stakes.iter().map(|stake| stake.amount).sum();
+        
+        pool.stakes.push((principal, UserStakes { stakes, total_staked }));
+    }
+    
+    Ok(pool)
+}
+
+// Shared by deposit and its ICRC-21 consent message
+fn validate_deposit(request: &DepositRequest) -> Result<(), String> {
+    if is_paused() {
+        return Err("Pool is paused".to_string());
+    }
+    if lifecycle() == PoolLifecycle::WindDown {
+        return Err("Pool is winding down and no longer accepts deposits".to_string());
+    }
+    
+    // Validate lock period
+    if ![90, 180, 360].contains(&request.lock_period_days) {
+        return Err("Invalid lock period. Must be 90, 180, or 360 days".to_string());
+    }
+    
+    // Validate amount
+    let fee = transfer_fee();
+    if request.amount < fee {
+        return Err(format!("Amount must be at least {} to cover fees", format_amount(fee)));
+    }
+    
+    // Validate label
+    if let Some(label) = &request.label {
+        validate_label(label)?;
+    }
+    
+    // Check canister balance (simulated)
+    let available_balance = CANISTER_BALANCE.with(|balance| *balance.borrow());
+    if request.amount > available_balance {
+        return Err("Insufficient canister balance for deposit".to_string());
+    }
+    
+    Ok(())
+}
+
+// Shared by withdraw and its ICRC-21 consent message; returns the stake to be paid out
+fn validate_withdraw(owner: &Principal, stake_index: usize, current_time: u64) -> Result<StakeInfo, WithdrawError> {
+    if is_paused() {
+        return Err("Pool is paused".to_string().into());
+    }
+    if lifecycle() == PoolLifecycle::Bootstrap {
+        return Err("Withdrawals open once the pool leaves bootstrap".to_string().into());
+    }
+    
+    STAKES.with(|stakes| {
+        let stakes_map = stakes.borrow();
+        let user_stakes = stakes_map.get(owner).ok_or("No stakes found for user".to_string())?;
+        let stake = user_stakes.stakes.get(stake_index).ok_or("Invalid stake index".to_string())?;
+        
+        if let Some(lien) = LIENS.with(|liens| liens.borrow().get(&stake.id).cloned()) {
+            return Err(format!(
+                "Stake is pledged as collateral to {} and cannot be withdrawn until the lien is released",
+                lien.lien_holder
+            ).into());
+        }
+        
+        if current_time < stake.unlock_time {
+            return Err(WithdrawError::Locked {
+                stake_id: stake.id,
+                unlock_at: stake.unlock_time,
+                seconds_remaining: stake.unlock_time - current_time,
+                lock_tier: stake.lock_period_days,
+            });
+        }
+        
+        Ok(stake.clone())
+    })
+}
+
+fn get_account_identifier(subaccount: &Subaccount) -> AccountIdentifier {
+    format!("account_{}", hex::encode(subaccount))  // Convert subaccount to string
+}
+
+// Rejects a deposit that would leave `owner` above the concentration cap
+fn check_concentration(owner: &Principal, amount: u64) -> Result<(), String> {
+    let Some(limit) = CONCENTRATION_LIMIT.with(|limit| *limit.borrow()) else {
+        return Ok(());
+    };
+    
+    let pool_total = TOTAL_POOL_AMOUNT.with(|total| *total.borrow()) as u128;
+    let owner_total = STAKES.with(|stakes| {
+        stakes.borrow().get(owner).map_or(0, |user| user.total_staked)
+    }) as u128;
+    // The first deposit is always 100% of the pool, so the cap can only apply once the pool is funded
+    let new_pool_total = pool_total + amount as u128;
+    if pool_total == 0 || new_pool_total < limit.min_pool_amount as u128 {
+        return Ok(());
+    }
+    
+    let max_bps = limit.max_share_bps as u128;
+    if (owner_total + amount as u128) * 10_000 <= new_pool_total * max_bps {
+        return Ok(());
+    }
+    
+    // Largest x with (owner + x) / (pool + x) <= cap
+    let headroom = if max_bps >= 10_000 {
+        u64::MAX as u128
+    } else {
+        (pool_total * max_bps).saturating_sub(owner_total * 10_000) / (10_000 - max_bps)
+    };
+    Err(format!(
+        "Deposit would exceed the {} bps concentration cap; the most this account can deposit now is {}",
+        limit.max_share_bps, format_amount(headroom.min(u64::MAX as u128) as u64)
+    ))
+}
+
+// Linking moves stakes that are already in the pool, so only the account's share changes
+fn check_link_concentration(owner: &Principal, combined_total: u64) -> Result<(), String> {
+    let Some(limit) = CONCENTRATION_LIMIT.with(|limit| *limit.borrow()) else {
+        return Ok(());
+    };
+    
+    let pool_total = TOTAL_POOL_AMOUNT.with(|total| *total.borrow()) as u128;
+    if pool_total < limit.min_pool_amount as u128 {
+        return Ok(());
+    }
+    if combined_total as u128 * 10_000 > pool_total * limit.max_share_bps as u128 {
+        return Err(format!(
+            "Linking would leave account {} above the {} bps concentration cap",
+            owner, limit.max_share_bps
+        ));
+    }
+    Ok(())
+}
+
+// Rejects a deposit whose amount would overflow the account or pool total
+fn check_capacity(owner: &Principal, amount: u64) -> Result<(), String> {
+    let owner_total = STAKES.with(|stakes| stakes.borrow().get(owner).map_or(0, |user| user.total_staked));
+    let pool_total = TOTAL_POOL_AMOUNT.with(|total| *total.borrow());
+    if owner_total.checked_add(amount).is_none() || pool_total.checked_add(amount).is_none() {
+        return Err("Deposit would overflow the pool's amount range".to_string());
+    }
+    Ok(())
+}
+
+// Creates a stake owned by `owner`; `depositor` is the principal that pays for it
+fn create_stake(owner: Principal, depositor: Principal, request: DepositRequest) -> Result<DepositResult, String> {
+    validate_deposit(&request)?;
+    check_capacity(&owner, request.amount)?;
+    check_concentration(&owner, request.amount)?;
+    debit_simulated_balance(depositor, request.amount)?;
+    
+    stake_or_hold(owner, depositor, request)
+}
+
+// Simulated transfers (in real implementation, these would be actual ICP transfers). Faucet
+// builds also track each account's dripped funds, so deposits can only spend what it was given.
+#[cfg_attr(not(feature = "faucet"), allow(unused_variables))]
+fn debit_simulated_balance(payer: Principal, amount: u64) -> Result<(), String> {
+    #[cfg(feature = "faucet")]
+    {
+        let owner = account_owner(payer);
+        let balance = FAUCET_BALANCES.with(|balances| balances.borrow().get(&owner).copied().unwrap_or(0));
+        if balance < amount {
+            return Err(format!("Insufficient faucet balance: {} available", format_amount(balance)));
+        }
+        set_faucet_balance(owner, balance - amount);
+    }
+    
+    CANISTER_BALANCE.with(|balance| {
+        *balance.borrow_mut() -= amount;
+    });
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "faucet"), allow(unused_variables))]
+fn credit_simulated_balance(recipient: Principal, amount: u64) {
+    #[cfg(feature = "faucet")]
+    {
+        let owner = account_owner(recipient);
+        let balance = FAUCET_BALANCES.with(|balances| balances.borrow().get(&owner).copied().unwrap_or(0));
+        set_faucet_balance(owner, balance + amount);
+    }
+    
+    CANISTER_BALANCE.with(|balance| {
+        *balance.borrow_mut() += amount;
+    });
+}
+
+// Large deposits are held without starting the lock until an admin approves them
+fn stake_or_hold(owner: Principal, depositor: Principal, request: DepositRequest) -> Result<DepositResult, String> {
+    let gated = WHALE_GATE_THRESHOLD.with(|threshold| threshold.borrow().is_some_and(|t| request.amount > t));
+    if gated {
+        let id = NEXT_PENDING_DEPOSIT_ID.with(|n| {
+            let current = *n.borrow();
+            *n.borrow_mut() = current + 1;
+            current
+        });
+        insert_pending_deposit(PendingDeposit {
+            id,
+            owner,
+            depositor,
+            amount: request.amount,
+            lock_period_days: request.lock_period_days,
+            label: request.label,
+            submitted_at: get_current_time(),
+        });
+        refresh_state_hash();
+        
+        return Ok(DepositResult::PendingApproval { pending_deposit_id: id, amount: request.amount });
+    }
+    
+    open_stake(owner, depositor, request).map(DepositResult::Staked)
+}
+
+// Records a stake whose funds have already been received
+fn open_stake(owner: Principal, depositor: Principal, request: DepositRequest) -> Result<StakeReceipt, String> {
+    check_capacity(&owner, request.amount)?;
+    
+    // Generate unique subaccount for this stake
+    let stake_subaccount = generate_subaccount(&owner);
+    let account_id = get_account_identifier(&stake_subaccount);
+    
+    let current_time = get_current_time();
+    let unlock_time = current_time + (request.lock_period_days as u64 * SECONDS_PER_LOCK_DAY);
+    advance_maturity(current_time);
+    
+    let stake_id = next_stake_id();
+    let stake_info = StakeInfo {
+        id: stake_id,
+        amount: request.amount,
+        lock_period_days: request.lock_period_days,
+        stake_time: current_time,
+        unlock_time,
+        subaccount: stake_subaccount,
+        label: request.label,
+    };
+    
+    // Update state
+    STAKES.with(|stakes| {
+        let mut stakes_map = stakes.borrow_mut();
+        let user_stakes = stakes_map.entry(owner).or_insert(UserStakes {
+            stakes: Vec::new(),
+            total_staked: 0,
+        });
+        
+        record_stake_added(&owner, &stake_info);
+        user_stakes.stakes.push(stake_info);
+        user_stakes.total_staked = user_stakes.total_staked.checked_add(request.amount)
+            .expect("capacity was checked above");
+    });
+    
+    TOTAL_POOL_AMOUNT.with(|total| {
+        let mut total = total.borrow_mut();
+        *total = total.checked_add(request.amount).expect("capacity was checked above");
+    });
+    
+    record_history(owner, HistoryKind::Deposit, stake_id, request.amount, 0, depositor, None);
+    if account_owner(depositor) != owner {
+        record_history(
+            account_owner(depositor),
+            HistoryKind::FundedDeposit { beneficiary: owner },
+            stake_id,
+            request.amount,
+            0,
+            depositor,
+            None,
+        );
+    }
+    
+    refresh_state_hash();
+    record_instructions(Operation::Deposit);
+    
+    Ok(StakeReceipt {
+        stake_id,
+        amount: request.amount,
+        lock_tier: request.lock_period_days,
+        stake_time: current_time,
+        unlock_at: unlock_time,
+        seconds_remaining: unlock_time - current_time,
+        account_id,
+    })
+}
+
+#[update]
+async fn deposit(request: DepositRequest) -> Result<DepositResult, String> {
+    let caller = ic_cdk::caller();
+    create_stake(account_owner(caller), caller, request)
+}
+
+#[update]
+async fn deposit_for(beneficiary: Principal, request: DepositRequest) -> Result<DepositResult, String> {
+    let caller = ic_cdk::caller();
+    if beneficiary == Principal::anonymous() {
+        return Err("Beneficiary cannot be the anonymous principal".to_string());
+    }
+    create_stake(account_owner(beneficiary), caller, request)
+}
+
+#[update]
+fn set_whale_gate_threshold(threshold: Option<u64>) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    WHALE_GATE_THRESHOLD.with(|t| *t.borrow_mut() = threshold);
+    Ok(match threshold {
+        Some(threshold) => format!("Deposits above {} now require approval", format_amount(threshold)),
+        None => "Whale gate disabled".to_string(),
+    })
+}
+
+#[update]
+fn approve_pending_deposit(id: u64) -> Result<StakeReceipt, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    if is_paused() {
+        return Err("Pool is paused".to_string());
+    }
+    if lifecycle() == PoolLifecycle::WindDown {
+        return Err("Pool is winding down and no longer accepts deposits".to_string());
+    }
+    
+    // The pool may have changed since submission, so the cap is checked again
+    let deposit = PENDING_DEPOSITS.with(|pending| pending.borrow().get(&id).cloned())
+        .ok_or("Pending deposit not found".to_string())?;
+    check_capacity(&deposit.owner, deposit.amount)?;
+    check_concentration(&deposit.owner, deposit.amount)?;
+    remove_pending_deposit(id);
+    let owner = deposit.owner;
+    let amount = deposit.amount;
+    
+    // The lock starts now, not when the deposit was submitted
+    let result = open_stake(deposit.owner, deposit.depositor, DepositRequest {
+        amount: deposit.amount,
+        lock_period_days: deposit.lock_period_days,
+        label: deposit.label,
+    })?;
+    notify(owner, format!("Your deposit of {} was approved and is now locked", format_amount(amount)));
+    Ok(result)
+}
+
+#[update]
+fn set_concentration_limit(limit: Option<ConcentrationLimit>) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    if let Some(limit) = &limit {
+        if limit.max_share_bps == 0 || limit.max_share_bps > 10_000 {
+            return Err("max_share_bps must be between 1 and 10000".to_string());
+        }
+    }
+    
+    CONCENTRATION_LIMIT.with(|l| *l.borrow_mut() = limit);
+    Ok("Concentration limit updated".to_string())
+}
+
+#[query]
+fn get_concentration_stats() -> ConcentrationStats {
+    let total_amount = TOTAL_POOL_AMOUNT.with(|total| *total.borrow());
+    let share_bps = |amount: u64| {
+        (amount as u128 * 10_000).checked_div(total_amount as u128).unwrap_or(0) as u32
+    };
+    
+    let mut holdings: Vec<(Principal, u64)> = STAKES.with(|stakes| {
+        stakes.borrow().iter()
+            .filter(|(_, user)| user.total_staked > 0)
+            .map(|(principal, user)| (*principal, user.total_staked))
+            .collect()
+    });
+    holdings.sort_by_key(|(_, amount)| std::cmp::Reverse(*amount));
+    
+    let herfindahl_index_bps = holdings.iter()
+        .map(|(_, amount)| share_bps(*amount) as u64 * share_bps(*amount) as u64 / 10_000)
+        .sum::<u64>() as u32;
+    
+    ConcentrationStats {
+        limit: CONCENTRATION_LIMIT.with(|limit| *limit.borrow()),
+        total_amount,
+        largest_share_bps: holdings.first().map_or(0, |(_, amount)| share_bps(*amount)),
+        herfindahl_index_bps,
+        top_stakers: holdings.iter()
+            .take(10)
+            .map(|(principal, amount)| (*principal, *amount, share_bps(*amount)))
+            .collect(),
+    }
+}
+
+// Returns a pending deposit's funds; callable by an admin, the depositor or the stake owner
+#[update]
+fn refund_pending_deposit(id: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let deposit = PENDING_DEPOSITS.with(|pending| pending.borrow().get(&id).cloned())
+        .ok_or("Pending deposit not found".to_string())?;
+    
+    let caller_account = account_owner(caller);
+    if !is_authorized(&caller) && caller_account != deposit.owner && caller_account != account_owner(deposit.depositor) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    Ok(release_pending_deposit(&deposit))
+}
+
+fn release_pending_deposit(deposit: &PendingDeposit) -> String {
+    remove_pending_deposit(deposit.id);
+    credit_simulated_balance(deposit.depositor, deposit.amount);
+    refresh_state_hash();
+    
+    notify(deposit.owner, format!("Your pending deposit of {} was refunded", format_amount(deposit.amount)));
+    format!("Refunded {} to {}", format_amount(deposit.amount), deposit.depositor)
+}
+
+// Admins see every pending deposit; other callers see the ones they own or paid for
+#[query]
+fn get_pending_deposits() -> Vec<PendingDeposit> {
+    let caller = ic_cdk::caller();
+    let admin = is_authorized(&caller);
+    let caller_account = account_owner(caller);
+    
+    PENDING_DEPOSITS.with(|pending| {
+        pending.borrow().values()
+            .filter(|deposit| {
+                admin || deposit.owner == caller_account || account_owner(deposit.depositor) == caller_account
+            })
+            .cloned()
+            .collect()
+    })
+}
+
+// Pays out an unlocked stake of `owner` to `destination`; `actor` is the principal that made the call
+fn withdraw_stake(owner: Principal, actor: Principal, stake_index: usize, destination: Account) -> Result<WithdrawReceipt, WithdrawError> {
+    let current_time = get_current_time();
+    let stake = validate_withdraw(&owner, stake_index, current_time)?;
+    Ok(pay_out_stake(owner, actor, stake_index, stake, destination)?)
+}
+
+// Removes `stake` (at `stake_index`) and pays it out without any lock or lifecycle checks
+fn pay_out_stake(owner: Principal, actor: Principal, stake_index: usize, stake: StakeInfo, destination: Account) -> Result<WithdrawReceipt, String> {
+    let current_time = get_current_time();
+    let amount = stake.amount;
+    let fee = transfer_fee();
+    let transfer_amount = amount.saturating_sub(fee);
+    
+    // Checked before any mutation so a stake worth no more than the fee stays intact
+    if transfer_amount == 0 {
+        return Err("Insufficient amount to cover transfer fee".to_string());
+    }
+    
+    advance_maturity(current_time);
+    
+    // Remove stake and update totals
+    STAKES.with(|stakes| {
+        if let Some(user_stakes) = stakes.borrow_mut().get_mut(&owner) {
+            record_stake_removed(&owner, &stake);
+            user_stakes.stakes.remove(stake_index);
+            user_stakes.total_staked -= amount;
+        }
+    });
+    
+    TOTAL_POOL_AMOUNT.with(|total| {
+        *total.borrow_mut() -= amount;
+    });
+    
+    credit_simulated_balance(destination.owner, transfer_amount);
+    
+    SCHEDULED_WITHDRAWALS.with(|scheduled| scheduled.borrow_mut().remove(&stake.id));
+    
+    let large_withdrawal_threshold = WEBHOOK_CONFIG.with(|config| {
+        config.borrow().as_ref().map_or(u64::MAX, |c| c.large_withdrawal_threshold)
+    });
+    if amount >= large_withdrawal_threshold {
+        enqueue_alert("large_withdrawal", format!(
+            "{{\"owner\":\"{}\",\"stake_id\":{},\"amount\":{}}}", 
+            owner, stake.id, amount
+        ));
+    }
+    
+    record_history(owner, HistoryKind::Withdraw, stake.id, transfer_amount, fee, actor, Some(destination.clone()));
+    refresh_state_hash();
+    record_instructions(Operation::Withdraw);
+    
+    Ok(WithdrawReceipt {
+        stake_id: stake.id,
+        amount,
+        fee,
+        payout: transfer_amount,
+        destination,
+    })
+}
+
+#[update]
+async fn withdraw(request: WithdrawRequest) -> Result<WithdrawReceipt, WithdrawError> {
+    let caller = ic_cdk::caller();
+    let destination = validate_account(request.destination.unwrap_or(Account { owner: caller, subaccount: None }))?;
+    withdraw_stake(account_owner(caller), caller, request.stake_index, destination)
+}
+
+#[update]
+fn set_stake_label(stake_index: usize, label: Option<String>) -> Result<String, String> {
+    let owner = account_owner(ic_cdk::caller());
+    
+    if let Some(label) = &label {
+        validate_label(label)?;
+    }
+    
+    STAKES.with(|stakes| {
+        let mut stakes_map = stakes.borrow_mut();
+        let user_stakes = stakes_map.get_mut(&owner).ok_or("No stakes found for user".to_string())?;
+        let stake = user_stakes.stakes.get_mut(stake_index).ok_or("Invalid stake index".to_string())?;
+        stake.label = label;
+        Ok(format!("Updated label for stake {}", stake_index))
+    })
+}
+
+#[query]
+fn get_my_preferences() -> UserPreferences {
+    let owner = account_owner(ic_cdk::caller());
+    USER_PREFERENCES.with(|prefs| {
+        prefs.borrow().get(&owner).cloned().unwrap_or_default()
+    })
+}
+
+#[update]
+fn set_my_preferences(preferences: UserPreferences) -> Result<String, String> {
+    let owner = account_owner(ic_cdk::caller());
+    
+    USER_PREFERENCES.with(|prefs| {
+        prefs.borrow_mut().insert(owner, preferences);
+    });
+    
+    Ok("Preferences updated".to_string())
+}
+
+fn build_stake_view(index: usize, stake: &StakeInfo, current_time: u64) -> StakeView {
+    let seconds_until_unlock = stake.unlock_time.saturating_sub(current_time);
+    let status = if seconds_until_unlock == 0 {
+        StakeStatus::Unlocked
+    } else {
+        StakeStatus::Locked
+    };
+    
+    StakeView {
+        index,
+        info: stake.clone(),
+        account_id: get_account_identifier(&stake.subaccount),
+        status,
+        seconds_until_unlock,
+        payout_at_maturity: stake.amount.saturating_sub(transfer_fee()),
+    }
+}
+
+#[query]
+fn get_user_stakes(user: Principal) -> Option<UserStakesView> {
+    let user = account_owner(user);
+    let current_time = get_current_time();
+    STAKES.with(|stakes| {
+        stakes.borrow().get(&user).map(|user_stakes| UserStakesView {
+            stakes: user_stakes.stakes
+                .iter()
+                .enumerate()
+                .map(|(index, stake)| build_stake_view(index, stake, current_time))
+                .collect(),
+            total_staked: user_stakes.total_staked,
+            token: token_metadata(),
+        })
+    })
+}
+
+#[query]
+fn get_my_stakes() -> Option<UserStakesView> {
+    let caller = ic_cdk::caller();
+    get_user_stakes(caller)
+}
+
+#[query]
+fn get_my_history() -> Vec<HistoryEntry> {
+    let owner = account_owner(ic_cdk::caller());
+    USER_HISTORY.with(|history| {
+        history.borrow().get(&owner).cloned().unwrap_or_default()
+    })
+}
+
+// Totals the history entries with from_ts <= timestamp <= to_ts
+fn build_statement(owner: Principal, from_ts: u64, to_ts: u64) -> Result<Statement, String> {
+    if from_ts > to_ts {
+        return Err("from_ts must not be after to_ts".to_string());
+    }
+    
+    let entries: Vec<HistoryEntry> = USER_HISTORY.with(|history| {
+        history.borrow().get(&owner).map_or_else(Vec::new, |entries| {
+            entries.iter()
+                .filter(|entry| entry.timestamp >= from_ts && entry.timestamp <= to_ts)
+                .cloned()
+                .collect()
+        })
+    });
+    
+    let overflow = || "Statement totals overflow; request a shorter range".to_string();
+    let mut deposits = 0u64;
+    let mut withdrawals = 0u64;
+    let mut funded_for_others = 0u64;
+    let mut fees_paid = 0u64;
+    for entry in &entries {
+        let total = match entry.kind {
+            HistoryKind::Deposit => &mut deposits,
+            HistoryKind::Withdraw => &mut withdrawals,
+            HistoryKind::FundedDeposit { .. } => &mut funded_for_others,
+        };
+        *total = total.checked_add(entry.amount).ok_or_else(overflow)?;
+        fees_paid = fees_paid.checked_add(entry.fee).ok_or_else(overflow)?;
+    }
+    let net_position_change = i64::try_from(deposits as i128 - withdrawals as i128 - fees_paid as i128)
+        .map_err(|_| overflow())?;
+    
+    Ok(Statement {
+        from_ts,
+        to_ts,
+        deposits,
+        withdrawals,
+        funded_for_others,
+        fees_paid,
+        net_position_change,
+        entries,
+    })
+}
+
+#[query]
+fn get_my_statement(from_ts: u64, to_ts: u64) -> Result<Statement, String> {
+    build_statement(account_owner(ic_cdk::caller()), from_ts, to_ts)
+}
+
+// Mints a token for downloading the caller's statement over HTTP, where calls are anonymous
+#[update]
+async fn create_statement_token() -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous principal cannot export statements".to_string());
+    }
+    let owner = account_owner(caller);
+    
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| format!("Failed to generate token: {:?} {}", code, msg))?;
+    let token = hex::encode(&random_bytes[..16]);
+    
+    let current_time = get_current_time();
+    STATEMENT_TOKENS.with(|tokens| {
+        let mut tokens = tokens.borrow_mut();
+        tokens.retain(|_, (_, expires_at)| *expires_at > current_time);
+        tokens.insert(token.clone(), (owner, current_time + STATEMENT_TOKEN_TTL_SECONDS));
+    });
+    
+    Ok(token)
+}
+
+fn history_kind_name(kind: &HistoryKind) -> &'static str {
+    match kind {
+        HistoryKind::Deposit => "deposit",
+        HistoryKind::Withdraw => "withdraw",
+        HistoryKind::FundedDeposit { .. } => "funded_deposit",
+    }
+}
+
+fn statement_csv(statement: &Statement) -> String {
+    let mut csv = String::from("timestamp,kind,stake_id,amount,fee,actor,beneficiary,destination_owner,destination_subaccount\n");
+    for entry in &statement.entries {
+        let beneficiary = match &entry.kind {
+            HistoryKind::FundedDeposit { beneficiary } => beneficiary.to_string(),
+            _ => String::new(),
+        };
+        let (destination_owner, destination_subaccount) = match &entry.destination {
+            Some(account) => (account.owner.to_string(), account.subaccount.map(hex::encode).unwrap_or_default()),
+            None => (String::new(), String::new()),
+        };
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{}\n",
+            entry.timestamp, history_kind_name(&entry.kind), entry.stake_id, entry.amount, entry.fee,
+            entry.actor, beneficiary, destination_owner, destination_subaccount
+        ));
+    }
+    csv
+}
+
+fn statement_json(statement: &Statement) -> String {
+    let entries: Vec<String> = statement.entries.iter().map(|entry| {
+        let beneficiary = match &entry.kind {
+            HistoryKind::FundedDeposit { beneficiary } => format!("\"{}\"", beneficiary),
+            _ => "null".to_string(),
+        };
+        let destination = match &entry.destination {
+            Some(account) => format!(
+                "{{\"owner\":\"{}\",\"subaccount\":{}}}",
+                account.owner,
+                account.subaccount.map_or("null".to_string(), |sub| format!("\"{}\"", hex::encode(sub)))
+            ),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"timestamp\":{},\"kind\":\"{}\",\"stake_id\":{},\"amount\":{},\"fee\":{},\"actor\":\"{}\",\"beneficiary\":{},\"destination\":{}}}",
+            entry.timestamp, history_kind_name(&entry.kind), entry.stake_id, entry.amount, entry.fee,
+            entry.actor, beneficiary, destination
+        )
+    }).collect();
+    
+    format!(
+        "{{\"from_ts\":{},\"to_ts\":{},\"deposits\":{},\"withdrawals\":{},\"funded_for_others\":{},\"fees_paid\":{},\"net_position_change\":{},\"entries\":[{}]}}",
+        statement.from_ts, statement.to_ts, statement.deposits, statement.withdrawals,
+        statement.funded_for_others, statement.fees_paid, statement.net_position_change, entries.join(",")
+    )
+}
+
+fn text_response(status_code: u16, body: String) -> HttpResponse {
+    HttpResponse {
+        status_code,
+        headers: vec![("Content-Type".to_string(), "text/plain; charset=utf-8".to_string())],
+        body: body.into_bytes(),
+    }
+}
+
+// Serves `/statement?token=...&from=...&to=...&format=csv|json`. `principal` is optional and
+// must match the token's account when given. Responses are not certified.
+#[query]
+fn http_request(request: HttpRequest) -> HttpResponse {
+    let (path, query) = request.url.split_once('?').unwrap_or((request.url.as_str(), ""));
+    if request.method != "GET" || path != "/statement" {
+        return text_response(404, "Not found".to_string());
+    }
+    
+    let params: BTreeMap<&str, &str> = query.split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .collect();
+    
+    let token = params.get("token").copied().unwrap_or_default();
+    let current_time = get_current_time();
+    let Some(owner) = STATEMENT_TOKENS.with(|tokens| {
+        tokens.borrow().get(token).filter(|(_, expires_at)| *expires_at > current_time).map(|(owner, _)| *owner)
+    }) else {
+        return text_response(401, "Missing, invalid or expired token".to_string());
+    };
+    
+    if let Some(principal) = params.get("principal") {
+        if Principal::from_text(principal).map(account_owner) != Ok(owner) {
+            return text_response(403, "Token does not grant access to this principal".to_string());
+        }
+    }
+    
+    let parse_ts = |name: &str, default: u64| match params.get(name) {
+        Some(value) => value.parse::<u64>().map_err(|_| format!("Invalid {} timestamp", name)),
+        None => Ok(default),
+    };
+    let range = parse_ts("from", 0).and_then(|from| Ok((from, parse_ts("to", current_time)?)));
+    let statement = match range.and_then(|(from, to)| build_statement(owner, from, to)) {
+        Ok(statement) => statement,
+        Err(e) => return text_response(400, e),
+    };
+    
+    let (content_type, body) = match params.get("format").copied().unwrap_or("json") {
+        "csv" => ("text/csv; charset=utf-8", statement_csv(&statement)),
+        "json" => ("application/json", statement_json(&statement)),
+        _ => return text_response(400, "Format must be csv or json".to_string()),
+    };
+    
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Cache-Control".to_string(), "no-store".to_string()),
+        ],
+        body: body.into_bytes(),
+    }
+}
+
+#[query]
+fn get_my_portfolio() -> Portfolio {
+    let caller = ic_cdk::caller();
+    let stakes = get_user_stakes(caller).map(|view| view.stakes).unwrap_or_default();
+    
+    let mut locked_amount = 0;
+    let mut unlocked_amount = 0;
+    for view in &stakes {
+        match view.status {
+            StakeStatus::Locked => locked_amount += view.info.amount,
+            StakeStatus::Unlocked => unlocked_amount += view.info.amount,
+        }
+    }
+    
+    let next_unlock_time = stakes
+        .iter()
+        .filter(|view| view.status == StakeStatus::Locked)
+        .map(|view| view.info.unlock_time)
+        .min();
+    
+    Portfolio {
+        total_staked: locked_amount + unlocked_amount,
+        locked_amount,
+        unlocked_amount,
+        next_unlock_time,
+        stakes,
+        preferences: get_my_preferences(),
+        token: token_metadata(),
+    }
+}
+
+#[update]
+async fn start_link() -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous principal cannot link accounts".to_string());
+    }
+    let owner = account_owner(caller);
+    
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| format!("Failed to generate link code: {:?} {}", code, msg))?;
+    let code = hex::encode(&random_bytes[..16]);
+    
+    let current_time = get_current_time();
+    LINK_CHALLENGES.with(|challenges| {
+        let mut challenges = challenges.borrow_mut();
+        challenges.retain(|_, challenge| challenge.expires_at > current_time);
+        challenges.insert(code.clone(), LinkChallenge {
+            owner,
+            expires_at: current_time + LINK_CHALLENGE_TTL_SECONDS,
+        });
+    });
+    
+    Ok(code)
+}
+
+#[update]
+fn confirm_link(code: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous principal cannot link accounts".to_string());
+    }
+    
+    let challenge = LINK_CHALLENGES.with(|challenges| challenges.borrow_mut().remove(&code))
+        .ok_or("Invalid link code".to_string())?;
+    if get_current_time() >= challenge.expires_at {
+        return Err("Link code has expired".to_string());
+    }
+    
+    let owner = challenge.owner;
+    if caller == owner {
+        return Err("Cannot link a principal to itself".to_string());
+    }
+    // The code's owner may have been linked to another account since it was issued
+    if account_owner(owner) != owner {
+        return Err("Link code owner is linked to another account".to_string());
+    }
+    if account_owner(caller) != caller {
+        return Err("Principal is already linked to an account".to_string());
+    }
+    if ACCOUNT_LINKS.with(|links| links.borrow().values().any(|o| *o == caller)) {
+        return Err("Principal owns an account with linked principals".to_string());
+    }
+    let moved_total = STAKES.with(|stakes| stakes.borrow().get(&caller).map_or(0, |user| user.total_staked));
+    let owner_total = STAKES.with(|stakes| stakes.borrow().get(&owner).map_or(0, |user| user.total_staked));
+    let combined_total = owner_total.checked_add(moved_total)
+        .ok_or("Combined stakes would overflow the account total".to_string())?;
+    if moved_total > 0 {
+        check_link_concentration(&owner, combined_total)?;
+    }
+    #[cfg(feature = "faucet")]
+    let combined_faucet_balance = {
+        let balance = |principal: &Principal| {
+            FAUCET_BALANCES.with(|balances| balances.borrow().get(principal).copied().unwrap_or(0))
+        };
+        balance(&owner).checked_add(balance(&caller))
+            .ok_or("Combined faucet balance would overflow".to_string())?
+    };
+    
+    // Consolidate the caller's existing stakes into the owner's account
+    STAKES.with(|stakes| {
+        let mut stakes_map = stakes.borrow_mut();
+        if let Some(moved) = stakes_map.remove(&caller) {
+            let user_stakes = stakes_map.entry(owner).or_insert(UserStakes {
+                stakes: Vec::new(),
+                total_staked: 0,
+            });
+            for stake in &moved.stakes {
+                remove_digest(stake_digest(&caller, stake));
+                add_digest(stake_digest(&owner, stake));
+            }
+            user_stakes.stakes.extend(moved.stakes);
+            user_stakes.stakes.sort_by_key(|stake| stake.id);
+            user_stakes.total_staked = combined_total;
+        }
+    });
+    
+    USER_HISTORY.with(|history| {
+        let mut history = history.borrow_mut();
+        if let Some(moved) = history.remove(&caller) {
+            let entries = history.entry(owner).or_default();
+            entries.extend(moved);
+            entries.sort_by_key(|entry| entry.timestamp);
+        }
+    });
+    // The owner's existing settings take precedence; the inbox keeps the newest messages of both
+    USER_PREFERENCES.with(|prefs| {
+        let mut prefs = prefs.borrow_mut();
+        if let Some(moved) = prefs.remove(&caller) {
+            prefs.entry(owner).or_insert(moved);
+        }
+    });
+    NOTIFICATIONS.with(|notifications| {
+        let mut notifications = notifications.borrow_mut();
+        if let Some(moved) = notifications.remove(&caller) {
+            let inbox = notifications.entry(owner).or_default();
+            inbox.extend(moved);
+            inbox.sort_by_key(|notification| notification.timestamp);
+            let excess = inbox.len().saturating_sub(MAX_NOTIFICATIONS);
+            inbox.drain(..excess);
+        }
+    });
+    
+    SCHEDULED_WITHDRAWALS.with(|scheduled| {
+        for entry in scheduled.borrow_mut().values_mut().filter(|entry| entry.owner == caller) {
+            entry.owner = owner;
+        }
+    });
+    RECURRING_DEPOSITS.with(|plans| {
+        for plan in plans.borrow_mut().values_mut().filter(|plan| plan.owner == caller) {
+            plan.owner = owner;
+        }
+    });
+    PENDING_DEPOSITS.with(|pending| {
+        for deposit in pending.borrow_mut().values_mut().filter(|deposit| deposit.owner == caller) {
+            remove_digest(pending_deposit_digest(deposit));
+            deposit.owner = owner;
+            add_digest(pending_deposit_digest(deposit));
+        }
+    });
+    LIENS.with(|liens| {
+        for lien in liens.borrow_mut().values_mut().filter(|lien| lien.owner == caller) {
+            remove_digest(lien_digest(lien));
+            lien.owner = owner;
+            add_digest(lien_digest(lien));
+        }
+    });
+    VOUCHERS.with(|vouchers| {
+        for voucher in vouchers.borrow_mut().values_mut().filter(|voucher| voucher.sender == caller) {
+            remove_digest(voucher_digest(voucher));
+            voucher.sender = owner;
+            add_digest(voucher_digest(voucher));
+        }
+    });
+    
+    // Grants the owner already made take precedence over the caller's
+    OPERATOR_GRANTS.with(|grants| {
+        let mut grants = grants.borrow_mut();
+        let moved: Vec<_> = grants.keys().filter(|(o, _)| *o == caller).copied().collect();
+        for key in moved {
+            let grant = grants.remove(&key).expect("key was just listed");
+            if key.1 != owner {
+                grants.entry((owner, key.1)).or_insert(grant);
+            }
+        }
+    });
+    UNLOCK_CALLBACKS.with(|callbacks| {
+        let mut callbacks = callbacks.borrow_mut();
+        if let Some(method) = callbacks.remove(&caller) {
+            if is_canister_principal(&owner) {
+                callbacks.entry(owner).or_insert(method);
+            }
+        }
+    });
+    // The later drip wins so linking cannot be used to skip the cooldown
+    #[cfg(feature = "faucet")]
+    {
+        if let Some(balance) = FAUCET_BALANCES.with(|balances| balances.borrow_mut().remove(&caller)) {
+            remove_digest(faucet_balance_digest(&caller, balance));
+            set_faucet_balance(owner, combined_faucet_balance);
+        }
+        if let Some(time) = FAUCET_LAST_DRIP.with(|drips| drips.borrow_mut().remove(&caller)) {
+            remove_digest(faucet_drip_digest(&caller, time));
+            let existing = FAUCET_LAST_DRIP.with(|drips| drips.borrow().get(&owner).copied().unwrap_or(0));
+            set_faucet_last_drip(owner, existing.max(time));
+        }
+    }
+    
+    // Codes the caller issued would otherwise link further principals to a non-owner
+    LINK_CHALLENGES.with(|challenges| challenges.borrow_mut().retain(|_, challenge| challenge.owner != caller));
+    ACCOUNT_LINKS.with(|links| links.borrow_mut().insert(caller, owner));
+    refresh_state_hash();
+    
+    Ok(format!("Linked {} to account {}", caller, owner))
+}
+
+#[update]
+fn unlink_principal(principal: Principal) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let owner = account_owner(caller);
+    
+    // The account owner can remove any linked principal, a linked principal only itself
+    if caller != owner && caller != principal {
+        return Err("Unauthorized".to_string());
+    }
+    
+    ACCOUNT_LINKS.with(|links| {
+        let mut links = links.borrow_mut();
+        if links.get(&principal) != Some(&owner) {
+            return Err("Principal is not linked to this account".to_string());
+        }
+        links.remove(&principal);
+        Ok(format!("Unlinked {} from account {}", principal, owner))
+    })
+}
+
+#[query]
+fn get_linked_principals() -> Vec<Principal> {
+    let owner = account_owner(ic_cdk::caller());
+    let mut principals = vec![owner];
+    ACCOUNT_LINKS.with(|links| {
+        principals.extend(links.borrow().iter().filter(|(_, o)| **o == owner).map(|(linked, _)| *linked));
+    });
+    principals
+}
+
+#[update]
+fn schedule_withdrawal(stake_id: u64, destination: Option<Account>) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let owner = account_owner(caller);
+    
+    let stake_index = find_stake_index(&owner, stake_id).ok_or("Stake not found".to_string())?;
+    // Such a payout would always fail, so the entry would be retried forever
+    let amount = STAKES.with(|stakes| stakes.borrow()[&owner].stakes[stake_index].amount);
+    if amount <= transfer_fee() {
+        return Err("Insufficient amount to cover transfer fee".to_string());
+    }
+    
+    let destination = validate_account(destination.unwrap_or(Account { owner: caller, subaccount: None }))?;
+    SCHEDULED_WITHDRAWALS.with(|scheduled| {
+        scheduled.borrow_mut().insert(stake_id, ScheduledWithdrawal {
+            stake_id,
+            owner,
+            destination,
+            scheduled_by: caller,
+            created_at: get_current_time(),
+            last_error: None,
+        });
+    });
+    
+    Ok(format!("Stake {} will be paid out automatically when it unlocks", stake_id))
+}
+
+#[update]
+fn cancel_scheduled_withdrawal(stake_id: u64) -> Result<String, String> {
+    let owner = account_owner(ic_cdk::caller());
+    SCHEDULED_WITHDRAWALS.with(|scheduled| {
+        let mut scheduled = scheduled.borrow_mut();
+        match scheduled.get(&stake_id) {
+            Some(entry) if entry.owner == owner => {
+                scheduled.remove(&stake_id);
+                Ok(format!("Cancelled scheduled withdrawal of stake {}", stake_id))
+            }
+            _ => Err("No scheduled withdrawal for this stake".to_string()),
+        }
+    })
+}
+
+#[query]
+fn get_my_scheduled_withdrawals() -> Vec<ScheduledWithdrawal> {
+    let owner = account_owner(ic_cdk::caller());
+    SCHEDULED_WITHDRAWALS.with(|scheduled| {
+        scheduled.borrow().values().filter(|entry| entry.owner == owner).cloned().collect()
+    })
+}
+
+#[update]
+fn create_recurring_deposit(amount: u64, interval_seconds: u64, lock_period_days: u32, allowance: u64) -> Result<u64, String> {
+    let owner = account_owner(ic_cdk::caller());
+    
+    if interval_seconds < MIN_RECURRING_INTERVAL_SECONDS {
+        return Err(format!("Interval must be at least {} seconds", MIN_RECURRING_INTERVAL_SECONDS));
+    }
+    if allowance < amount {
+        return Err("Allowance must cover at least one deposit".to_string());
+    }
+    validate_deposit(&DepositRequest { amount, lock_period_days, label: None })?;
+    
+    let id = NEXT_RECURRING_ID.with(|n| {
+        let current = *n.borrow();
+        *n.borrow_mut() = current + 1;
+        current
+    });
+    let current_time = get_current_time();
+    
+    RECURRING_DEPOSITS.with(|plans| {
+        plans.borrow_mut().insert(id, RecurringDeposit {
+            id,
+            owner,
+            amount,
+            interval_seconds,
+            lock_period_days,
+            allowance_remaining: allowance,
+            next_run_at: current_time,  // First pull happens on the next maturity run
+            status: RecurringStatus::Active,
+            deposits_made: 0,
+            created_at: current_time,
+        });
+    });
+    
+    Ok(id)
+}
+
+// Adds to the plan's allowance and resumes it if it was paused
+#[update]
+fn top_up_recurring_deposit(id: u64, additional_allowance: u64) -> Result<String, String> {
+    let owner = account_owner(ic_cdk::caller());
+    RECURRING_DEPOSITS.with(|plans| {
+        let mut plans = plans.borrow_mut();
+        let plan = plans.get_mut(&id)
+            .filter(|plan| plan.owner == owner)
+            .ok_or("Recurring deposit not found".to_string())?;
+        
+        plan.allowance_remaining += additional_allowance;
+        if plan.allowance_remaining >= plan.amount {
+            plan.status = RecurringStatus::Active;
+            plan.next_run_at = plan.next_run_at.max(get_current_time());
+        }
+        Ok(format!("Recurring deposit {} allowance is now {}", id, format_amount(plan.allowance_remaining)))
+    })
+}
+
+#[update]
+fn cancel_recurring_deposit(id: u64) -> Result<String, String> {
+    let owner = account_owner(ic_cdk::caller());
+    RECURRING_DEPOSITS.with(|plans| {
+        let mut plans = plans.borrow_mut();
+        match plans.get(&id) {
+            Some(plan) if plan.owner == owner => {
+                plans.remove(&id);
+                Ok(format!("Cancelled recurring deposit {}", id))
+            }
+            _ => Err("Recurring deposit not found".to_string()),
+        }
+    })
+}
+
+#[query]
+fn get_my_recurring_deposits() -> Vec<RecurringDeposit> {
+    let owner = account_owner(ic_cdk::caller());
+    RECURRING_DEPOSITS.with(|plans| {
+        plans.borrow().values().filter(|plan| plan.owner == owner).cloned().collect()
+    })
+}
+
+#[query]
+fn get_my_notifications() -> Vec<Notification> {
+    let owner = account_owner(ic_cdk::caller());
+    NOTIFICATIONS.with(|notifications| {
+        notifications.borrow().get(&owner).cloned().unwrap_or_default()
+    })
+}
+
+fn hash_voucher_code(code: &str) -> String {
+    hex::encode(Sha256::digest(code.trim().as_bytes()))
+}
+
+// Funds a stake up front and returns the code that claims it; the code is shown only once
+#[update]
+async fn create_voucher(amount: u64, lock_period_days: u32) -> Result<String, String> {
+    let sender = account_owner(ic_cdk::caller());
+    if sender == Principal::anonymous() {
+        return Err("Anonymous principal cannot create vouchers".to_string());
+    }
+    
+    let (random_bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| format!("Failed to generate voucher code: {:?} {}", code, msg))?;
+    let code = hex::encode(&random_bytes[..16]);
+    
+    // Validated after the await so the balance check sees current state
+    validate_deposit(&DepositRequest { amount, lock_period_days, label: None })?;
+    debit_simulated_balance(sender, amount)?;
+    
+    let current_time = get_current_time();
+    let id = NEXT_VOUCHER_ID.with(|n| {
+        let current = *n.borrow();
+        *n.borrow_mut() = current + 1;
+        current
+    });
+    let code_hash = hash_voucher_code(&code);
+    insert_voucher(Voucher {
+        id,
+        sender,
+        amount,
+        lock_period_days,
+        code_hash,
+        created_at: current_time,
+        expires_at: current_time + VOUCHER_TTL_SECONDS,
+    });
+    refresh_state_hash();
+    
+    Ok(code)
+}
+
+#[update]
+fn redeem_voucher(code: String) -> Result<DepositResult, String> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous principal cannot redeem vouchers".to_string());
+    }
+    let owner = account_owner(caller);
+    
+    let code_hash = hash_voucher_code(&code);
+    let voucher = VOUCHERS.with(|vouchers| vouchers.borrow().get(&code_hash).cloned())
+        .ok_or("Invalid voucher code".to_string())?;
+    if get_current_time() >= voucher.expires_at {
+        return Err("Voucher has expired".to_string());
+    }
+    if is_paused() {
+        return Err("Pool is paused".to_string());
+    }
+    if lifecycle() == PoolLifecycle::WindDown {
+        return Err("Pool is winding down and no longer accepts deposits".to_string());
+    }
+    check_capacity(&owner, voucher.amount)?;
+    check_concentration(&owner, voucher.amount)?;
+    
+    remove_voucher(&code_hash);
+    let result = stake_or_hold(owner, voucher.sender, DepositRequest {
+        amount: voucher.amount,
+        lock_period_days: voucher.lock_period_days,
+        label: None,
+    })?;
+    notify(voucher.sender, format!("Voucher {} was redeemed by {}", voucher.id, owner));
+    Ok(result)
+}
+
+// Returns the funds of an unredeemed voucher to its sender once it expires or the pool winds down
+#[update]
+fn reclaim_voucher(id: u64) -> Result<String, ReclaimError> {
+    let sender = account_owner(ic_cdk::caller());
+    let voucher = VOUCHERS.with(|vouchers| {
+        vouchers.borrow().values().find(|voucher| voucher.id == id && voucher.sender == sender).cloned()
+    }).ok_or("Voucher not found".to_string())?;
+    let current_time = get_current_time();
+    if current_time < voucher.expires_at && lifecycle() != PoolLifecycle::WindDown {
+        return Err(ReclaimError::NotExpired {
+            voucher_id: id,
+            expires_at: voucher.expires_at,
+            seconds_remaining: voucher.expires_at - current_time,
+        });
+    }
+    
+    remove_voucher(&voucher.code_hash);
+    credit_simulated_balance(voucher.sender, voucher.amount);
+    refresh_state_hash();
+    
+    Ok(format!("Reclaimed {} from voucher {}", format_amount(voucher.amount), id))
+}
+
+#[query]
+fn get_my_vouchers() -> Vec<Voucher> {
+    let sender = account_owner(ic_cdk::caller());
+    VOUCHERS.with(|vouchers| {
+        vouchers.borrow().values().filter(|voucher| voucher.sender == sender).cloned().collect()
+    })
+}
+
+#[update]
+fn add_lending_canister(lender: Principal) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    if !LENDING_CANISTERS.with(|lenders| lenders.borrow_mut().insert(lender)) {
+        return Err("Principal is already a lending canister".to_string());
+    }
+    Ok(format!("Added lending canister {}", lender))
+}
+
+// Existing liens stay valid so loans already made against them are not stranded
+#[update]
+fn remove_lending_canister(lender: Principal) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    if !LENDING_CANISTERS.with(|lenders| lenders.borrow_mut().remove(&lender)) {
+        return Err("Principal is not a lending canister".to_string());
+    }
+    Ok(format!("Removed lending canister {}", lender))
+}
+
+// Called by the stake owner to pledge a locked stake to a whitelisted lender
+#[update]
+fn lock_as_collateral(stake_id: u64, lien_holder: Principal, amount: u64) -> Result<String, String> {
+    let owner = account_owner(ic_cdk::caller());
+    if is_paused() {
+        return Err("Pool is paused".to_string());
+    }
+    if !LENDING_CANISTERS.with(|lenders| lenders.borrow().contains(&lien_holder)) {
+        return Err("Lien holder is not a whitelisted lending canister".to_string());
+    }
+    
+    let stake_index = find_stake_index(&owner, stake_id).ok_or("Stake not found".to_string())?;
+    let stake = STAKES.with(|stakes| stakes.borrow().get(&owner).map(|user| user.stakes[stake_index].clone()))
+        .ok_or("Stake not found".to_string())?;
+    if stake.unlock_time <= get_current_time() {
+        return Err("Only locked stakes can be used as collateral".to_string());
+    }
+    if amount == 0 || amount > stake.amount {
+        return Err(format!("Lien amount must be between 1 and {} base units", stake.amount));
+    }
+    if LIENS.with(|liens| liens.borrow().contains_key(&stake_id)) {
+        return Err("Stake already has a lien".to_string());
+    }
+    
+    insert_lien(Lien {
+        stake_id,
+        owner,
+        lien_holder,
+        amount,
+        created_at: get_current_time(),
+    });
+    refresh_state_hash();
+    
+    Ok(format!("Stake {} pledged to {} for {}", stake_id, lien_holder, format_amount(amount)))
+}
+
+#[update]
+fn release_lien(stake_id: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    match LIENS.with(|liens| liens.borrow().get(&stake_id).map(|lien| lien.lien_holder)) {
+        Some(holder) if holder == caller => {
+            remove_lien(stake_id);
+        }
+        Some(_) => return Err("Only the lien holder can release a lien".to_string()),
+        None => return Err("Stake has no lien".to_string()),
+    }
+    refresh_state_hash();
+    
+    Ok(format!("Lien on stake {} released", stake_id))
+}
+
+// Pays the lien amount to its holder; returns what is left of the stake, if anything
+fn settle_lien(lien: &Lien, stake_index: usize) -> Result<Option<StakeInfo>, String> {
+    let stake = STAKES.with(|stakes| stakes.borrow().get(&lien.owner).map(|user| user.stakes[stake_index].clone()))
+        .ok_or("Stake not found".to_string())?;
+    let holder_account = Account { owner: lien.lien_holder, subaccount: None };
+    
+    // Removed before the payout refreshes the state hash, and restored if the payout fails. A
+    // remainder no larger than the fee could never be withdrawn, so it goes with the rest.
+    let fee = transfer_fee();
+    if stake.amount.saturating_sub(lien.amount) <= fee {
+        remove_lien(lien.stake_id);
+        if let Err(e) = pay_out_stake(lien.owner, lien.lien_holder, stake_index, stake, holder_account) {
+            insert_lien(lien.clone());
+            return Err(e);
+        }
+        return Ok(None);
+    }
+    
+    // Partial foreclosure: the owner keeps the rest of the stake
+    let paid = lien.amount.saturating_sub(fee);
+    if paid == 0 {
+        return Err("Insufficient amount to cover transfer fee".to_string());
+    }
+    remove_lien(lien.stake_id);
+    let remaining = STAKES.with(|stakes| {
+        let mut stakes = stakes.borrow_mut();
+        let user_stakes = stakes.get_mut(&lien.owner).expect("stake owner exists");
+        remove_digest(stake_digest(&lien.owner, &user_stakes.stakes[stake_index]));
+        user_stakes.stakes[stake_index].amount -= lien.amount;
+        add_digest(stake_digest(&lien.owner, &user_stakes.stakes[stake_index]));
+        user_stakes.total_staked -= lien.amount;
+        user_stakes.stakes[stake_index].clone()
+    });
+    TOTAL_POOL_AMOUNT.with(|total| *total.borrow_mut() -= lien.amount);
+    credit_simulated_balance(lien.lien_holder, paid);
+    
+    record_history(lien.owner, HistoryKind::Withdraw, lien.stake_id, paid, fee, lien.lien_holder, Some(holder_account));
+    refresh_state_hash();
+    
+    Ok(Some(remaining))
+}
+
+// Lets the lien holder collect once the stake has unlocked, e.g. after the loan defaulted
+#[update]
+fn foreclose_lien(stake_id: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if is_paused() {
+        return Err("Pool is paused".to_string());
+    }
+    if lifecycle() == PoolLifecycle::Bootstrap {
+        return Err("Withdrawals open once the pool leaves bootstrap".to_string());
+    }
+    
+    let lien = LIENS.with(|liens| liens.borrow().get(&stake_id).cloned()).ok_or("Stake has no lien".to_string())?;
+    if lien.lien_holder != caller {
+        return Err("Only the lien holder can foreclose".to_string());
+    }
+    
+    let stake_index = find_stake_index(&lien.owner, stake_id).ok_or("Stake not found".to_string())?;
+    let unlock_time = STAKES.with(|stakes| stakes.borrow().get(&lien.owner).map(|user| user.stakes[stake_index].unlock_time))
+        .ok_or("Stake not found".to_string())?;
+    if get_current_time() < unlock_time {
+        return Err("Stake is still locked; foreclosure is possible once it unlocks".to_string());
+    }
+    
+    settle_lien(&lien, stake_index)?;
+    notify(lien.owner, format!("Lender {} foreclosed {} of stake {}", caller, format_amount(lien.amount), stake_id));
+    Ok(format!("Foreclosed {} of stake {}", format_amount(lien.amount), stake_id))
+}
+
+// Owners see liens on their stakes; lending canisters see the liens they hold
+#[query]
+fn get_liens() -> Vec<Lien> {
+    let caller = ic_cdk::caller();
+    let owner = account_owner(caller);
+    LIENS.with(|liens| {
+        liens.borrow().values()
+            .filter(|lien| lien.owner == owner || lien.lien_holder == caller)
+            .cloned()
+            .collect()
+    })
+}
+
+// Opaque ids, which canisters get, end with the 0x01 class byte
+fn is_canister_principal(principal: &Principal) -> bool {
+    let bytes = principal.as_slice();
+    bytes.len() == 10 && bytes[9] == 0x01
+}
+
+// Lets a canister staker be called with `(UnlockNotice)` on `method` when each of its stakes unlocks
+#[update]
+fn set_unlock_callback(method: Option<String>) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    let owner = account_owner(caller);
+    if !is_canister_principal(&owner) {
+        return Err("Unlock callbacks are only available to canister accounts".to_string());
+    }
+    
+    UNLOCK_CALLBACKS.with(|callbacks| {
+        let mut callbacks = callbacks.borrow_mut();
+        match method {
+            Some(method) => {
+                if method.trim().is_empty() {
+                    return Err("Callback method must not be empty".to_string());
+                }
+                callbacks.insert(owner, method.clone());
+                Ok(format!("Unlocks will be sent to {}.{}", owner, method))
+            }
+            None => {
+                callbacks.remove(&owner);
+                Ok("Unlock callback removed".to_string())
+            }
+        }
+    })
+}
+
+#[query]
+fn get_my_unlock_callback() -> Option<String> {
+    let owner = account_owner(ic_cdk::caller());
+    UNLOCK_CALLBACKS.with(|callbacks| callbacks.borrow().get(&owner).cloned())
+}
+
+// One-way calls: a staker that rejects or traps does not hold up the pool, and is not retried
+fn process_unlock_callbacks(current_time: u64) {
+    let cursor = UNLOCK_NOTIFY_CURSOR.with(|cursor| *cursor.borrow());
+    let callbacks = UNLOCK_CALLBACKS.with(|callbacks| callbacks.borrow().clone());
+    if callbacks.is_empty() {
+        return;
+    }
+    
+    let mut due: Vec<(Principal, UnlockNotice)> = STAKES.with(|stakes| {
+        let stakes = stakes.borrow();
+        callbacks.keys()
+            .filter_map(|owner| stakes.get(owner).map(|user| (owner, user)))
+            .flat_map(|(owner, user)| user.stakes.iter().map(move |stake| (*owner, stake)))
+            .filter(|(_, stake)| {
+                stake.unlock_time <= current_time && (stake.unlock_time, stake.id) > cursor
+            })
+            .map(|(owner, stake)| (owner, UnlockNotice {
+                stake_id: stake.id,
+                amount: stake.amount,
+                unlock_time: stake.unlock_time,
+            }))
+            .collect()
+    });
+    due.sort_by_key(|(_, notice)| (notice.unlock_time, notice.stake_id));
+    due.truncate(MAX_JOBS_PER_RUN);
+    
+    for (owner, notice) in &due {
+        let method = &callbacks[owner];
+        if let Err(code) = ic_cdk::api::call::notify(*owner, method, (notice.clone(),)) {
+            ic_cdk::println!("Unlock callback for stake {} to {} failed: {:?}", notice.stake_id, owner, code);
+        }
+    }
+    
+    if let Some((_, last)) = due.last() {
+        UNLOCK_NOTIFY_CURSOR.with(|cursor| *cursor.borrow_mut() = (last.unlock_time, last.stake_id));
+    }
+}
+
+#[update]
+fn grant_permission(operator: Principal, permission: Permission) -> Result<String, String> {
+    let owner = account_owner(ic_cdk::caller());
+    if operator == owner || operator == Principal::anonymous() {
+        return Err("Invalid operator".to_string());
+    }
+    
+    OPERATOR_GRANTS.with(|grants| {
+        grants.borrow_mut().insert((owner, operator), OperatorGrant {
+            permission: permission.clone(),
+            withdrawn: 0,
+            granted_at: get_current_time(),
+        });
+    });
+    
+    Ok(format!("Granted {:?} to {}", permission, operator))
+}
+
+#[update]
+fn revoke_permission(operator: Principal) -> Result<String, String> {
+    let owner = account_owner(ic_cdk::caller());
+    OPERATOR_GRANTS.with(|grants| grants.borrow_mut().remove(&(owner, operator)))
+        .ok_or("Operator has no permission on this account".to_string())?;
+    Ok(format!("Revoked permissions of {}", operator))
+}
+
+#[query]
+fn get_my_operators() -> Vec<(Principal, OperatorGrant)> {
+    let owner = account_owner(ic_cdk::caller());
+    OPERATOR_GRANTS.with(|grants| {
+        grants.borrow()
+            .range((owner, Principal::management_canister())..)  // The empty principal sorts first
+            .take_while(|((o, _), _)| *o == owner)
+            .map(|((_, operator), grant)| (*operator, grant.clone()))
+            .collect()
+    })
+}
+
+#[update]
+async fn operator_deposit(owner: Principal, request: DepositRequest) -> Result<DepositResult, String> {
+    let owner = account_owner(owner);
+    let caller = ic_cdk::caller();
+    let grant = operator_grant(&owner, &caller)?;
+    if grant.permission == Permission::ReadOnly {
+        return Err("Operator is not allowed to deposit".to_string());
+    }
+    create_stake(owner, caller, request)
+}
+
+#[update]
+async fn operator_withdraw(owner: Principal, request: WithdrawRequest) -> Result<WithdrawReceipt, WithdrawError> {
+    let owner = account_owner(owner);
+    let caller = ic_cdk::caller();
+    let grant = operator_grant(&owner, &caller)?;
+    let limit = match grant.permission {
+        Permission::WithdrawUpTo { limit } => limit,
+        _ => return Err("Operator is not allowed to withdraw".to_string().into()),
+    };
+    
+    let stake = validate_withdraw(&owner, request.stake_index, get_current_time())?;
+    if grant.withdrawn + stake.amount > limit {
+        return Err(format!(
+            "Withdrawal exceeds operator limit. Remaining: {}", 
+            format_amount(limit.saturating_sub(grant.withdrawn))
+        ).into());
+    }
+    
+    // Operators may pick a subaccount but never send funds away from the owner
+    let destination = validate_account(request.destination.unwrap_or(Account { owner, subaccount: None }))?;
+    if destination.owner != owner {
+        return Err("Operators can only withdraw to the owner's accounts".to_string().into());
+    }
+    
+    let result = withdraw_stake(owner, caller, request.stake_index, destination)?;
+    OPERATOR_GRANTS.with(|grants| {
+        if let Some(grant) = grants.borrow_mut().get_mut(&(owner, caller)) {
+            grant.withdrawn += stake.amount;
+        }
+    });
+    Ok(result)
+}
+
+#[query]
+fn get_operator_view(owner: Principal) -> Result<Option<UserStakesView>, String> {
+    let owner = account_owner(owner);
+    operator_grant(&owner, &ic_cdk::caller())?;
+    Ok(get_user_stakes(owner))
+}
+
+#[query]
+fn get_pool_info() -> (u64, usize, usize) {
+    let total_amount = TOTAL_POOL_AMOUNT.with(|total| *total.borrow());
+    let total_stakers = STAKES.with(|stakes| stakes.borrow().len());
+    let total_stakes = STAKE_COUNT.with(|count| *count.borrow()) as usize;
+    (total_amount, total_stakers, total_stakes)
+}
+
+// ICP's own values until the ledger has been queried
+fn token_metadata() -> TokenMetadata {
+    TOKEN_METADATA.with(|metadata| metadata.borrow().clone()).unwrap_or_else(|| TokenMetadata {
+        ledger: Principal::from_text(ICP_LEDGER_CANISTER_ID).expect("valid ledger id"),
+        symbol: "ICP".to_string(),
+        decimals: 8,
+        fee: ICP_FEE,
+        logo_url: None,
+        fetched_at: None,
+    })
+}
+
+fn transfer_fee() -> u64 {
+    TOKEN_METADATA.with(|metadata| metadata.borrow().as_ref().map_or(ICP_FEE, |m| m.fee))
+}
+
+fn pool_holds_funds() -> bool {
+    TOTAL_POOL_AMOUNT.with(|total| *total.borrow()) > 0
+        || PENDING_DEPOSITS.with(|pending| !pending.borrow().is_empty())
+        || VOUCHERS.with(|vouchers| !vouchers.borrow().is_empty())
+}
+
+#[update]
+async fn refresh_token_metadata(ledger: Option<Principal>) -> Result<TokenMetadata, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    let current = token_metadata();
+    let ledger = ledger.unwrap_or(current.ledger);
+    // Stakes, pending deposits and vouchers are claims on the current ledger's token
+    if ledger != current.ledger && pool_holds_funds() {
+        return Err(format!("Cannot switch from ledger {} while the pool holds funds", current.ledger));
+    }
+    let (entries,): (Vec<(String, Icrc1MetadataValue)>,) = ic_cdk::call(ledger, "icrc1_metadata", ())
+        .await
+        .map_err(|(code, msg)| format!("Failed to fetch ledger metadata: {:?} {}", code, msg))?;
+    
+    // Deposits and other refreshes can run during the call, so checks are repeated against fresh state
+    let current = token_metadata();
+    let mut metadata = TokenMetadata {
+        ledger,
+        symbol: current.symbol,
+        decimals: current.decimals,
+        fee: current.fee,
+        logo_url: None,
+        fetched_at: Some(get_current_time()),
+    };
+    for (key, value) in entries {
+        match (key.as_str(), value) {
+            ("icrc1:symbol", Icrc1MetadataValue::Text(symbol)) => metadata.symbol = symbol,
+            ("icrc1:decimals", Icrc1MetadataValue::Nat(decimals)) => {
+                metadata.decimals = decimals.to_string().replace('_', "").parse().ok()
+                    .filter(|decimals| *decimals <= MAX_TOKEN_DECIMALS)
+                    .ok_or(format!("Ledger reported unsupported decimals {}", decimals))?;
+            }
+            ("icrc1:fee", Icrc1MetadataValue::Nat(fee)) => {
+                metadata.fee = fee.to_string().replace('_', "").parse()
+                    .map_err(|_| format!("Ledger reported unsupported fee {}", fee))?;
+            }
+            ("icrc1:logo", Icrc1MetadataValue::Text(logo)) => metadata.logo_url = Some(logo),
+            _ => {}
+        }
+    }
+    
+    if ledger != current.ledger && pool_holds_funds() {
+        return Err(format!("Cannot switch from ledger {} while the pool holds funds", current.ledger));
+    }
+    // Stored amounts are in base units, so changing the scale under open stakes would reprice them
+    if metadata.decimals != current.decimals && pool_holds_funds() {
+        return Err(format!(
+            "Ledger uses {} decimals but the pool holds amounts in {} decimals",
+            metadata.decimals, current.decimals
+        ));
+    }
+    
+    TOKEN_METADATA.with(|cached| *cached.borrow_mut() = Some(metadata.clone()));
+    Ok(metadata)
+}
+
+#[query]
+fn v1_get_pool_info() -> V1PoolInfo {
+    V1PoolInfo {
+        total_staked: TOTAL_POOL_AMOUNT.with(|total| *total.borrow()),
+        total_stakers: STAKES.with(|stakes| stakes.borrow().len()) as u64,
+        total_stakes: STAKE_COUNT.with(|count| *count.borrow()),
+        lock_periods_days: vec![90, 180, 360],
+        withdrawal_fee: transfer_fee(),
+        paused: is_paused(),
+    }
+}
+
+#[query]
+fn v1_get_user_position(user: Principal) -> Option<V1UserPosition> {
+    let account = account_owner(user);
+    let current_time = get_current_time();
+    STAKES.with(|stakes| {
+        stakes.borrow().get(&account).map(|user_stakes| V1UserPosition {
+            account,
+            total_staked: user_stakes.total_staked,
+            voting_power: account_voting_power(user_stakes, current_time),
+            stakes: user_stakes.stakes
+                .iter()
+                .map(|stake| V1Stake {
+                    id: stake.id,
+                    amount: stake.amount,
+                    lock_period_days: stake.lock_period_days,
+                    stake_time: stake.stake_time,
+                    unlock_time: stake.unlock_time,
+                })
+                .collect(),
+        })
+    })
+}
+
+#[query]
+fn get_pool_stats() -> PoolStats {
+    let current_time = get_current_time();
+    
+    // Stakes that unlocked since the last update call are still in PENDING_UNLOCKS
+    let newly_matured: u64 = PENDING_UNLOCKS.with(|pending| {
+        pending.borrow().range(..=current_time).map(|(_, count)| count).sum()
+    });
+    
+    PoolStats {
+        total_amount: TOTAL_POOL_AMOUNT.with(|total| *total.borrow()),
+        total_stakers: STAKES.with(|stakes| stakes.borrow().len()) as u64,
+        total_stakes: STAKE_COUNT.with(|count| *count.borrow()),
+        stakes_per_tier: TIER_COUNTS.with(|tiers| {
+            tiers.borrow().iter().map(|(tier, count)| (*tier, *count)).collect()
+        }),
+        matured_stakes: MATURED_COUNT.with(|count| *count.borrow()) + newly_matured,
+        token: token_metadata(),
+    }
+}
+
+// Test deployments only: tops up the simulated balance so deposit and withdraw can be tried end to end
+#[cfg(feature = "faucet")]
+#[update]
+fn faucet_drip() -> Result<String, FaucetError> {
+    let caller = ic_cdk::caller();
+    if caller == Principal::anonymous() {
+        return Err("Anonymous principal cannot use the faucet".to_string().into());
+    }
+    
+    let owner = account_owner(caller);
+    let current_time = get_current_time();
+    let last_drip = FAUCET_LAST_DRIP.with(|drips| drips.borrow().get(&owner).copied());
+    if let Some(last_drip) = last_drip {
+        let retry_at = last_drip + FAUCET_COOLDOWN_SECONDS;
+        if current_time < retry_at {
+            return Err(FaucetError::CoolingDown { retry_at, retry_after: retry_at - current_time });
+        }
+    }
+    
+    let amount = FAUCET_TOKENS as u64 * 10u64.pow(token_metadata().decimals as u32);
+    let balance = FAUCET_BALANCES.with(|balances| balances.borrow().get(&owner).copied().unwrap_or(0));
+    if balance.checked_add(amount).is_none() {
+        return Err("Faucet balance cannot hold another drip".to_string().into());
+    }
+    
+    set_faucet_last_drip(owner, current_time);
+    credit_simulated_balance(caller, amount);
+    refresh_state_hash();
+    
+    Ok(format!("Added {} of simulated balance", format_amount(amount)))
+}
+
+#[cfg(feature = "faucet")]
+#[query]
+fn get_my_faucet_balance() -> u64 {
+    let owner = account_owner(ic_cdk::caller());
+    FAUCET_BALANCES.with(|balances| balances.borrow().get(&owner).copied().unwrap_or(0))
+}
+
+#[query]
+fn get_balance_sheet() -> BalanceSheet {
+    let staker_principal = TOTAL_POOL_AMOUNT.with(|total| *total.borrow());
+    let pending_deposits: u64 = PENDING_DEPOSITS.with(|pending| {
+        pending.borrow().values().map(|deposit| deposit.amount).sum()
+    });
+    let voucher_escrow: u64 = VOUCHERS.with(|vouchers| {
+        vouchers.borrow().values().map(|voucher| voucher.amount).sum()
+    });
+    
+    BalanceSheet {
+        staker_principal,
+        pending_deposits,
+        voucher_escrow,
+        total_liabilities: staker_principal + pending_deposits + voucher_escrow,
+        pledged_as_collateral: LIENS.with(|liens| liens.borrow().values().map(|lien| lien.amount).sum()),
+    }
+}
+
+#[query]
+fn get_activity_report() -> Result<ActivityReport, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    let today = get_current_time() / 86_400;
+    ACTIVITY.with(|activity| {
+        let activity = activity.borrow();
+        let window = activity.range(today.saturating_sub(ACTIVITY_WINDOW_DAYS - 1)..=today);
+        
+        let mut days = Vec::new();
+        let mut totals: BTreeMap<Principal, (u64, u64)> = BTreeMap::new();
+        for (day, entry) in window {
+            days.push(DailyActivity {
+                day_start: day * 86_400,
+                deposit_volume: entry.deposit_volume,
+                deposit_count: entry.deposit_count,
+                withdraw_volume: entry.withdraw_volume,
+                withdraw_count: entry.withdraw_count,
+                active_principals: entry.flows.len() as u64,
+            });
+            for (principal, (deposited, withdrawn)) in &entry.flows {
+                let total = totals.entry(*principal).or_default();
+                total.0 += deposited;
+                total.1 += withdrawn;
+            }
+        }
+        
+        let top = |pick: fn(&(u64, u64)) -> u64| {
+            let mut flows: Vec<(Principal, u64)> = totals.iter()
+                .map(|(principal, flow)| (*principal, pick(flow)))
+                .filter(|(_, amount)| *amount > 0)
+                .collect();
+            flows.sort_by_key(|(_, amount)| std::cmp::Reverse(*amount));
+            flows.truncate(10);
+            flows
+        };
+        
+        Ok(ActivityReport {
+            days,
+            unique_active_principals: totals.len() as u64,
+            top_depositors: top(|flow| flow.0),
+            top_withdrawers: top(|flow| flow.1),
+        })
+    })
+}
+
+#[query]
+fn get_exit_queue_stats() -> ExitQueueStats {
+    let current_time = get_current_time();
+    let horizon = current_time + 30 * 24 * 60 * 60;
+    
+    // Scheduled withdrawals are the exit queue: each is paid out once its stake unlocks
+    let (queued_withdrawals, queued_amount, total_wait) = SCHEDULED_WITHDRAWALS.with(|scheduled| {
+        STAKES.with(|stakes| {
+            let stakes = stakes.borrow();
+            scheduled.borrow().values().fold((0u64, 0u64, 0u64), |(count, amount, wait), entry| {
+                let stake = stakes.get(&entry.owner)
+                    .and_then(|user| user.stakes.iter().find(|s| s.id == entry.stake_id));
+                match stake {
+                    Some(stake) => (
+                        count + 1,
+                        amount + stake.amount,
+                        wait + stake.unlock_time.saturating_sub(current_time),
+                    ),
+                    None => (count, amount, wait),
+                }
+            })
+        })
+    });
+    
+    let projected_inflow_30_days = STAKES.with(|stakes| {
+        stakes.borrow().values()
+            .flat_map(|user| user.stakes.iter())
+            .filter(|stake| stake.unlock_time > current_time && stake.unlock_time <= horizon)
+            .map(|stake| stake.amount)
+            .sum()
+    });
+    
+    ExitQueueStats {
+        queued_withdrawals,
+        queued_amount,
+        average_wait_seconds: total_wait.checked_div(queued_withdrawals).unwrap_or(0),
+        projected_inflow_30_days,
+    }
+}
+
+#[update]
+fn add_loss_reporter(reporter: Principal) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    LOSS_REPORTERS.with(|reporters| {
+        let mut reporters = reporters.borrow_mut();
+        if reporters.contains(&reporter) {
+            return Err("Principal is already a loss reporter".to_string());
+        }
+        reporters.push(reporter);
+        Ok(format!("Added loss reporter {}", reporter))
+    })
+}
+
+#[update]
+fn remove_loss_reporter(reporter: Principal) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    LOSS_REPORTERS.with(|reporters| {
+        let mut reporters = reporters.borrow_mut();
+        match reporters.iter().position(|p| *p == reporter) {
+            Some(index) => {
+                reporters.remove(index);
+                Ok(format!("Removed loss reporter {}", reporter))
+            }
+            None => Err("Principal is not a loss reporter".to_string()),
+        }
+    })
+}
+
+#[update]
+fn record_loss_event(request: LossEventRequest) -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) && !is_loss_reporter(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    if request.event_type.trim().is_empty() || request.product.trim().is_empty() {
+        return Err("Event type and product must not be empty".to_string());
+    }
+    
+    let current_time = get_current_time();
+    if request.event_time > current_time {
+        return Err("Event time cannot be in the future".to_string());
+    }
+    
+    LOSS_EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let id = events.len() as u64;
+        events.push(LossEvent {
+            id,
+            event_type: request.event_type,
+            product: request.product,
+            event_time: request.event_time,
+            severity: request.severity,
+            attestation_hash: request.attestation_hash,
+            reporter: caller,
+            recorded_at: current_time,
+        });
+        Ok(id)
+    })
+}
+
+#[query]
+fn get_loss_event(id: u64) -> Option<LossEvent> {
+    LOSS_EVENTS.with(|events| {
+        events.borrow().get(id as usize).cloned()
+    })
+}
+
+#[query]
+fn get_loss_events(product: Option<String>) -> Vec<LossEvent> {
+    LOSS_EVENTS.with(|events| {
+        events.borrow()
+            .iter()
+            .filter(|event| product.as_ref().is_none_or(|p| &event.product == p))
+            .cloned()
+            .collect()
+    })
+}
+
+fn snapshot_state() -> PoolState {
+    PoolState {
+        stakes: STAKES.with(|stakes| {
+            stakes.borrow().iter().map(|(p, s)| (*p, s.clone())).collect()
+        }),
+        total_pool_amount: TOTAL_POOL_AMOUNT.with(|total| *total.borrow()),
+        next_subaccount_nonce: NEXT_SUBACCOUNT_NONCE.with(|n| *n.borrow()),
+        next_stake_id: NEXT_STAKE_ID.with(|n| *n.borrow()),
+        authorized_principals: AUTHORIZED_PRINCIPALS.with(|auth| auth.borrow().clone()),
+        canister_balance: CANISTER_BALANCE.with(|balance| *balance.borrow()),
+        loss_reporters: LOSS_REPORTERS.with(|reporters| reporters.borrow().clone()),
+        loss_events: LOSS_EVENTS.with(|events| events.borrow().clone()),
+        preferences: USER_PREFERENCES.with(|prefs| {
+            prefs.borrow().iter().map(|(p, s)| (*p, s.clone())).collect()
+        }),
+        trusted_origins: TRUSTED_ORIGINS.with(|origins| origins.borrow().clone()),
+        account_links: ACCOUNT_LINKS.with(|links| {
+            links.borrow().iter().map(|(linked, owner)| (*linked, *owner)).collect()
+        }),
+        history: USER_HISTORY.with(|history| {
+            history.borrow().iter().map(|(p, h)| (*p, h.clone())).collect()
+        }),
+        operator_grants: OPERATOR_GRANTS.with(|grants| {
+            grants.borrow().iter().map(|(k, g)| (*k, g.clone())).collect()
+        }),
+        scheduled_withdrawals: SCHEDULED_WITHDRAWALS.with(|scheduled| {
+            scheduled.borrow().values().cloned().collect()
+        }),
+        recurring_deposits: RECURRING_DEPOSITS.with(|plans| plans.borrow().values().cloned().collect()),
+        next_recurring_id: NEXT_RECURRING_ID.with(|n| *n.borrow()),
+        notifications: NOTIFICATIONS.with(|notifications| {
+            notifications.borrow().iter().map(|(p, n)| (*p, n.clone())).collect()
+        }),
+        snapshots: SNAPSHOTS.with(|snapshots| snapshots.borrow().clone()),
+        next_snapshot_id: NEXT_SNAPSHOT_ID.with(|n| *n.borrow()),
+        webhook_config: WEBHOOK_CONFIG.with(|config| config.borrow().clone()),
+        webhook_queue: WEBHOOK_QUEUE.with(|queue| queue.borrow().clone()),
+        lifecycle: LIFECYCLE.with(|lifecycle| *lifecycle.borrow()),
+        distribution_in_progress: DISTRIBUTION_IN_PROGRESS.with(|d| *d.borrow()),
+        unlock_callbacks: UNLOCK_CALLBACKS.with(|callbacks| {
+            callbacks.borrow().iter().map(|(p, m)| (*p, m.clone())).collect()
+        }),
+        unlock_notify_cursor: UNLOCK_NOTIFY_CURSOR.with(|cursor| *cursor.borrow()),
+        scheduled_withdrawal_cursor: SCHEDULED_WITHDRAWAL_CURSOR.with(|cursor| *cursor.borrow()),
+        token_metadata: TOKEN_METADATA.with(|metadata| metadata.borrow().clone()),
+        lending_canisters: LENDING_CANISTERS.with(|lenders| lenders.borrow().iter().copied().collect()),
+        liens: LIENS.with(|liens| liens.borrow().values().cloned().collect()),
+        vouchers: VOUCHERS.with(|vouchers| vouchers.borrow().values().cloned().collect()),
+        activity: ACTIVITY.with(|activity| activity.borrow().iter().map(|(day, a)| (*day, a.clone())).collect()),
+        dead_letters: DEAD_LETTERS.with(|letters| letters.borrow().clone()),
+        next_dead_letter_id: NEXT_DEAD_LETTER_ID.with(|n| *n.borrow()),
+        next_voucher_id: NEXT_VOUCHER_ID.with(|n| *n.borrow()),
+        whale_gate_threshold: WHALE_GATE_THRESHOLD.with(|threshold| *threshold.borrow()),
+        concentration_limit: CONCENTRATION_LIMIT.with(|limit| *limit.borrow()),
+        pending_deposits: PENDING_DEPOSITS.with(|pending| pending.borrow().values().cloned().collect()),
+        next_pending_deposit_id: NEXT_PENDING_DEPOSIT_ID.with(|n| *n.borrow()),
+        #[cfg(feature = "faucet")]
+        faucet_balances: FAUCET_BALANCES.with(|balances| balances.borrow().iter().map(|(p, b)| (*p, *b)).collect()),
+        #[cfg(feature = "faucet")]
+        faucet_last_drip: FAUCET_LAST_DRIP.with(|drips| drips.borrow().iter().map(|(p, t)| (*p, *t)).collect()),
+    }
+}
+
+fn restore_state(state: PoolState) {
+    STAKES.with(|stakes| *stakes.borrow_mut() = state.stakes.into_iter().collect());
+    TOTAL_POOL_AMOUNT.with(|total| *total.borrow_mut() = state.total_pool_amount);
+    NEXT_SUBACCOUNT_NONCE.with(|n| *n.borrow_mut() = state.next_subaccount_nonce);
+    NEXT_STAKE_ID.with(|n| *n.borrow_mut() = state.next_stake_id);
+    AUTHORIZED_PRINCIPALS.with(|auth| *auth.borrow_mut() = state.authorized_principals);
+    CANISTER_BALANCE.with(|balance| *balance.borrow_mut() = state.canister_balance);
+    LOSS_REPORTERS.with(|reporters| *reporters.borrow_mut() = state.loss_reporters);
+    LOSS_EVENTS.with(|events| *events.borrow_mut() = state.loss_events);
+    USER_PREFERENCES.with(|prefs| *prefs.borrow_mut() = state.preferences.into_iter().collect());
+    TRUSTED_ORIGINS.with(|origins| *origins.borrow_mut() = state.trusted_origins);
+    ACCOUNT_LINKS.with(|links| *links.borrow_mut() = state.account_links.into_iter().collect());
+    USER_HISTORY.with(|history| *history.borrow_mut() = state.history.into_iter().collect());
+    OPERATOR_GRANTS.with(|grants| *grants.borrow_mut() = state.operator_grants.into_iter().collect());
+    SCHEDULED_WITHDRAWALS.with(|scheduled| {
+        *scheduled.borrow_mut() = state.scheduled_withdrawals
+            .into_iter()
+            .map(|entry| (entry.stake_id, entry))
+            .collect()
+    });
+    RECURRING_DEPOSITS.with(|plans| {
+        *plans.borrow_mut() = state.recurring_deposits.into_iter().map(|plan| (plan.id, plan)).collect()
+    });
+    NEXT_RECURRING_ID.with(|n| *n.borrow_mut() = state.next_recurring_id);
+    NOTIFICATIONS.with(|notifications| *notifications.borrow_mut() = state.notifications.into_iter().collect());
+    SNAPSHOTS.with(|snapshots| *snapshots.borrow_mut() = state.snapshots);
+    NEXT_SNAPSHOT_ID.with(|n| *n.borrow_mut() = state.next_snapshot_id);
+    WEBHOOK_CONFIG.with(|config| *config.borrow_mut() = state.webhook_config);
+    NEXT_WEBHOOK_EVENT_ID.with(|n| {
+        *n.borrow_mut() = state.webhook_queue.iter().map(|event| event.id + 1).max().unwrap_or(1)
+    });
+    WEBHOOK_QUEUE.with(|queue| *queue.borrow_mut() = state.webhook_queue);
+    LIFECYCLE.with(|lifecycle| *lifecycle.borrow_mut() = state.lifecycle);
+    DISTRIBUTION_IN_PROGRESS.with(|d| *d.borrow_mut() = state.distribution_in_progress);
+    UNLOCK_CALLBACKS.with(|callbacks| *callbacks.borrow_mut() = state.unlock_callbacks.into_iter().collect());
+    UNLOCK_NOTIFY_CURSOR.with(|cursor| *cursor.borrow_mut() = state.unlock_notify_cursor);
+    SCHEDULED_WITHDRAWAL_CURSOR.with(|cursor| *cursor.borrow_mut() = state.scheduled_withdrawal_cursor);
+    TOKEN_METADATA.with(|metadata| *metadata.borrow_mut() = state.token_metadata);
+    LENDING_CANISTERS.with(|lenders| *lenders.borrow_mut() = state.lending_canisters.into_iter().collect());
+    LIENS.with(|liens| *liens.borrow_mut() = state.liens.into_iter().map(|lien| (lien.stake_id, lien)).collect());
+    VOUCHERS.with(|vouchers| {
+        *vouchers.borrow_mut() = state.vouchers.into_iter().map(|voucher| (voucher.code_hash.clone(), voucher)).collect()
+    });
+    NEXT_VOUCHER_ID.with(|n| *n.borrow_mut() = state.next_voucher_id);
+    ACTIVITY.with(|activity| *activity.borrow_mut() = state.activity.into_iter().collect());
+    DEAD_LETTERS.with(|letters| *letters.borrow_mut() = state.dead_letters);
+    NEXT_DEAD_LETTER_ID.with(|n| *n.borrow_mut() = state.next_dead_letter_id);
+    WHALE_GATE_THRESHOLD.with(|threshold| *threshold.borrow_mut() = state.whale_gate_threshold);
+    CONCENTRATION_LIMIT.with(|limit| *limit.borrow_mut() = state.concentration_limit);
+    PENDING_DEPOSITS.with(|pending| {
+        *pending.borrow_mut() = state.pending_deposits.into_iter().map(|deposit| (deposit.id, deposit)).collect()
+    });
+    NEXT_PENDING_DEPOSIT_ID.with(|n| *n.borrow_mut() = state.next_pending_deposit_id);
+    #[cfg(feature = "faucet")]
+    FAUCET_BALANCES.with(|balances| *balances.borrow_mut() = state.faucet_balances.into_iter().collect());
+    #[cfg(feature = "faucet")]
+    FAUCET_LAST_DRIP.with(|drips| *drips.borrow_mut() = state.faucet_last_drip.into_iter().collect());
+    rebuild_stats();
+    refresh_state_hash();
+}
+
+#[query]
+fn get_state_hash() -> String {
+    STATE_HASH.with(|h| hex::encode(*h.borrow()))
+}
+
+// Freezes an encoded snapshot and returns its first chunk; later chunks come from the same snapshot
+#[update]
+fn begin_state_export() -> Result<StateChunk, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    let encoded = candid::encode_one(snapshot_state())
+        .map_err(|e| format!("Failed to encode state: {}", e))?;
+    let export_id = ic_cdk::api::time();  // Nanoseconds, so ids stay unique across upgrades
+    let snapshot_hash = hex::encode(Sha256::digest(&encoded));
+    STATE_EXPORT.with(|export| *export.borrow_mut() = Some((export_id, snapshot_hash, encoded)));
+    
+    export_state_chunk(export_id, 0)
+}
+
+#[query]
+fn export_state_chunk(export_id: u64, chunk_index: u64) -> Result<StateChunk, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    STATE_EXPORT.with(|export| {
+        let export = export.borrow();
+        let (id, snapshot_hash, encoded) = export.as_ref()
+            .filter(|(id, _, _)| *id == export_id)
+            .ok_or("Export not found; call begin_state_export to start a new one".to_string())?;
+        let total_chunks = encoded.len().div_ceil(EXPORT_CHUNK_SIZE) as u64;
+        
+        if chunk_index >= total_chunks {
+            return Err(format!("Invalid chunk index. State has {} chunks", total_chunks));
+        }
+        
+        let start = chunk_index as usize * EXPORT_CHUNK_SIZE;
+        let end = (start + EXPORT_CHUNK_SIZE).min(encoded.len());
+        
+        Ok(StateChunk {
+            export_id: *id,
+            snapshot_hash: snapshot_hash.clone(),
+            chunk_index,
+            total_chunks,
+            data: encoded[start..end].to_vec(),
+        })
+    })
+}
+
+// Lists stake subaccounts in (owner, stake id) order with the balance each should hold
+#[query]
+fn audit_accounts(page: u64) -> Result<AuditPage, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    let total_stakes = STAKE_COUNT.with(|count| *count.borrow()) as usize;
+    let total_pages = total_stakes.div_ceil(AUDIT_PAGE_SIZE).max(1) as u64;
+    if page >= total_pages {
+        return Err(format!("Invalid page. Audit has {} pages", total_pages));
+    }
+    
+    let accounts = STAKES.with(|stakes| {
+        stakes.borrow().iter()
+            .flat_map(|(owner, user)| user.stakes.iter().map(move |stake| (owner, stake)))
+            .skip(page as usize * AUDIT_PAGE_SIZE)
+            .take(AUDIT_PAGE_SIZE)
+            .map(|(owner, stake)| AccountAudit {
+                owner: *owner,
+                stake_id: stake.id,
+                subaccount: stake.subaccount,
+                account_id: get_account_identifier(&stake.subaccount),
+                expected_balance: stake.amount,
+            })
+            .collect()
+    });
+    
+    Ok(AuditPage {
+        page,
+        total_pages,
+        total_expected: TOTAL_POOL_AMOUNT.with(|total| *total.borrow()),
+        accounts,
+    })
+}
+
+#[update]
+fn pause() -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    PAUSED.with(|p| *p.borrow_mut() = true);
+    Ok("Pool paused".to_string())
+}
+
+#[update]
+fn unpause() -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    PAUSED.with(|p| *p.borrow_mut() = false);
+    Ok("Pool unpaused".to_string())
+}
+
+#[query]
+fn get_paused() -> bool {
+    is_paused()
+}
+
+// Lifecycle only moves forward: Bootstrap -> Active -> WindDown, or Bootstrap -> WindDown
+#[update]
+fn set_pool_lifecycle(next: PoolLifecycle) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    let current = lifecycle();
+    let allowed = matches!(
+        (current, next),
+        (PoolLifecycle::Bootstrap, PoolLifecycle::Active)
+            | (PoolLifecycle::Bootstrap, PoolLifecycle::WindDown)
+            | (PoolLifecycle::Active, PoolLifecycle::WindDown)
+    );
+    if !allowed {
+        return Err(format!("Cannot move pool from {:?} to {:?}", current, next));
+    }
+    
+    LIFECYCLE.with(|lifecycle| *lifecycle.borrow_mut() = next);
+    Ok(format!("Pool is now in {:?}", next))
+}
+
+#[query]
+fn get_pool_lifecycle() -> PoolLifecycle {
+    lifecycle()
+}
+
+// Starts paying out every stake regardless of lock time; the heartbeat works through it in batches
+#[update]
+fn distribute_remaining() -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    if lifecycle() != PoolLifecycle::WindDown {
+        return Err("Final distribution is only available while the pool is winding down".to_string());
+    }
+    if DISTRIBUTION_IN_PROGRESS.with(|d| *d.borrow()) {
+        return Err("Final distribution is already in progress".to_string());
+    }
+    
+    DISTRIBUTION_IN_PROGRESS.with(|d| *d.borrow_mut() = true);
+    Ok(format!(
+        "Final distribution started for {} stakes",
+        STAKE_COUNT.with(|count| *count.borrow())
+    ))
+}
+
+// Stakes are paid in full, which is their pro-rata share since the pool has no gains or losses to spread
+fn process_final_distribution() {
+    if !DISTRIBUTION_IN_PROGRESS.with(|d| *d.borrow()) || is_paused() {
+        return;
+    }
+    
+    let pending: Vec<PendingDeposit> = PENDING_DEPOSITS.with(|pending| {
+        pending.borrow().values().take(MAX_JOBS_PER_RUN).cloned().collect()
+    });
+    // Stakes worth no more than the transfer fee cannot be paid out and are left in place
+    let fee = transfer_fee();
+    let batch: Vec<(Principal, u64)> = STAKES.with(|stakes| {
+        stakes.borrow().iter()
+            .flat_map(|(owner, user)| {
+                user.stakes.iter().filter(|stake| stake.amount > fee).map(|stake| (*owner, stake.id))
+            })
+            .take(MAX_JOBS_PER_RUN - pending.len())
+            .collect()
+    });
+    
+    for deposit in &pending {
+        release_pending_deposit(deposit);
+    }
+    
+    for (owner, stake_id) in batch {
+        let Some(stake_index) = find_stake_index(&owner, stake_id) else {
+            continue;
+        };
+        let Some(stake) = STAKES.with(|stakes| {
+            stakes.borrow().get(&owner).and_then(|user| user.stakes.get(stake_index).cloned())
+        }) else {
+            continue;
+        };
+        
+        // The lien holder is paid first; foreclosing the whole stake leaves nothing for the owner
+        let stake = match LIENS.with(|liens| liens.borrow().get(&stake_id).cloned()) {
+            Some(lien) => match settle_lien(&lien, stake_index) {
+                Ok(Some(remaining)) => remaining,
+                Ok(None) => continue,
+                Err(e) => {
+                    ic_cdk::println!("Final distribution of lien on stake {} failed: {}", stake_id, e);
+                    continue;
+                }
+            },
+            None => stake,
+        };
+        
+        // Honor a destination the owner already scheduled
+        let destination = SCHEDULED_WITHDRAWALS.with(|scheduled| {
+            scheduled.borrow().get(&stake_id).map(|entry| entry.destination.clone())
+        }).unwrap_or(Account { owner, subaccount: None });
+        
+        match pay_out_stake(owner, ic_cdk::id(), stake_index, stake, destination) {
+            Ok(_) => notify(owner, format!("Stake {} was paid out in the pool's final distribution", stake_id)),
+            Err(e) => ic_cdk::println!("Final distribution of stake {} failed: {}", stake_id, e),
+        }
+    }
+    
+    let finished = PENDING_DEPOSITS.with(|pending| pending.borrow().is_empty())
+        && STAKES.with(|stakes| {
+            stakes.borrow().values().all(|user| user.stakes.iter().all(|stake| stake.amount <= fee))
+        });
+    if finished {
+        DISTRIBUTION_IN_PROGRESS.with(|d| *d.borrow_mut() = false);
+        ic_cdk::println!("Final distribution complete");
+    }
+}
+
+#[query]
+fn get_distribution_status() -> DistributionStatus {
+    DistributionStatus {
+        in_progress: DISTRIBUTION_IN_PROGRESS.with(|d| *d.borrow()),
+        stakes_remaining: STAKE_COUNT.with(|count| *count.borrow()),
+        amount_remaining: TOTAL_POOL_AMOUNT.with(|total| *total.borrow()),
+        pending_deposits_remaining: PENDING_DEPOSITS.with(|pending| pending.borrow().len()) as u64,
+    }
+}
+
+#[update]
+fn create_backup() -> Result<u64, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    let data = candid::encode_one(snapshot_state())
+        .map_err(|e| format!("Failed to encode state: {}", e))?;
+    
+    let id = NEXT_BACKUP_ID.with(|n| {
+        let current = *n.borrow();
+        *n.borrow_mut() = current + 1;
+        current
+    });
+    
+    BACKUPS.with(|backups| {
+        let mut backups = backups.borrow_mut();
+        if backups.len() >= MAX_BACKUPS {
+            backups.remove(0);
+        }
+        backups.push(Backup {
+            id,
+            created_at: get_current_time(),
+            data,
+        });
+    });
+    
+    record_instructions(Operation::CreateBackup);
+    Ok(id)
+}
+
+#[update]
+fn restore_backup(backup_id: u64) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    if !is_paused() {
+        return Err("Pool must be paused before restoring a backup".to_string());
+    }
+    
+    let data = BACKUPS.with(|backups| {
+        backups.borrow()
+            .iter()
+            .find(|b| b.id == backup_id)
+            .map(|b| b.data.clone())
+    }).ok_or("Backup not found".to_string())?;
+    
+    let mut state: PoolState = candid::decode_one(&data)
+        .map_err(|e| format!("Failed to decode backup: {}", e))?;
+    
+    // Never lock ourselves out: the restoring principal stays authorized
+    if !state.authorized_principals.contains(&caller) {
+        state.authorized_principals.push(caller);
+    }
+    
+    restore_state(state);
+    
+    Ok(format!("Restored backup {}", backup_id))
+}
+
+#[query]
+fn list_backups() -> Result<Vec<BackupInfo>, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    Ok(BACKUPS.with(|backups| {
+        backups.borrow()
+            .iter()
+            .map(|b| BackupInfo {
+                id: b.id,
+                created_at: b.created_at,
+                size_bytes: b.data.len() as u64,
+            })
+            .collect()
+    }))
+}
+
+#[query]
+fn get_invariant_violations() -> Result<Vec<String>, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    Ok(check_invariants())
+}
+
+#[query]
+fn measure_operation(operation: Operation) -> Result<OperationMetrics, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    Ok(OPERATION_METRICS.with(|metrics| {
+        metrics.borrow().get(&operation).cloned().unwrap_or_default()
+    }))
+}
+
+// Renders base units with the token's decimals and symbol, e.g. 150_000_000 -> "1.5 ICP"
+fn format_amount(amount: u64) -> String {
+    let token = token_metadata();
+    let scale = 10u128.pow(token.decimals as u32);
+    let whole = amount as u128 / scale;
+    let fraction = amount as u128 % scale;
+    if fraction == 0 {
+        return format!("{} {}", whole, token.symbol);
+    }
+    let fraction = format!("{:0width$}", fraction, width = token.decimals as usize);
+    format!("{}.{} {}", whole, fraction.trim_end_matches('0'), token.symbol)
+}
+
+// Display-only rendering of the seconds fields in results, e.g. seconds_remaining
+#[query]
+fn format_duration(seconds: u64, locale: Option<String>) -> Result<String, String> {
+    let locale = locale.unwrap_or_else(|| "en".to_string());
+    let units: [(u64, &str, &str); 4] = match locale.as_str() {
+        "iso8601" => {
+            let (days, rest) = (seconds / 86_400, seconds % 86_400);
+            return Ok(format!("P{}DT{}H{}M{}S", days, rest / 3600, rest % 3600 / 60, rest % 60));
+        }
+        "en" => [(86_400, "day", "days"), (3600, "hour", "hours"), (60, "minute", "minutes"), (1, "second", "seconds")],
+        "de" => [(86_400, "Tag", "Tage"), (3600, "Stunde", "Stunden"), (60, "Minute", "Minuten"), (1, "Sekunde", "Sekunden")],
+        "fr" => [(86_400, "jour", "jours"), (3600, "heure", "heures"), (60, "minute", "minutes"), (1, "seconde", "secondes")],
+        "es" => [(86_400, "día", "días"), (3600, "hora", "horas"), (60, "minuto", "minutos"), (1, "segundo", "segundos")],
+        _ => return Err("Unsupported locale. Use en, de, fr, es or iso8601".to_string()),
+    };
+    
+    // The two largest non-zero units are precise enough for display
+    let mut remaining = seconds;
+    let mut parts = Vec::new();
+    for (unit_seconds, singular, plural) in units {
+        let count = remaining / unit_seconds;
+        remaining %= unit_seconds;
+        if count > 0 && parts.len() < 2 {
+            parts.push(format!("{} {}", count, if count == 1 { singular } else { plural }));
+        }
+    }
+    if parts.is_empty() {
+        parts.push(format!("0 {}", units[3].2));
+    }
+    Ok(parts.join(" "))
+}
+
+fn deposit_consent_text(request: &DepositRequest) -> Result<String, String> {
+    validate_deposit(request)?;
+    
+    let mut text = format!(
+        "Lock {} for {} days. The stake cannot be withdrawn before it unlocks. A fee of {} is deducted on withdrawal.",
+        format_amount(request.amount), request.lock_period_days, format_amount(transfer_fee())
+    );
+    if let Some(label) = &request.label {
+        text.push_str(&format!(" Label: \"{}\".", label));
+    }
+    Ok(text)
+}
+
+fn withdraw_consent_text(caller: &Principal, request: &WithdrawRequest) -> Result<String, String> {
+    let destination = validate_account(
+        request.destination.clone().unwrap_or(Account { owner: *caller, subaccount: None })
+    )?;
+    let stake = validate_withdraw(&account_owner(*caller), request.stake_index, get_current_time())?;
+    let transfer_amount = stake.amount.saturating_sub(transfer_fee());
+    if transfer_amount == 0 {
+        return Err("Insufficient amount to cover transfer fee".to_string());
+    }
+    
+    Ok(format!(
+        "Withdraw stake #{} of {}. {} is sent to {} after the {} fee.",
+        request.stake_index, format_amount(stake.amount), format_amount(transfer_amount),
+        format_account(&destination), format_amount(transfer_fee())
+    ))
+}
+
+// Greedy word wrap into pages of at most `lines_per_page` lines
+fn paginate_consent_text(text: &str, characters_per_line: u16, lines_per_page: u16) -> Vec<LineDisplayPage> {
+    let width = characters_per_line.max(1) as usize;
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = String::new();
+    
+    for word in text.split_whitespace() {
+        let mut word = word.to_string();
+        while word.chars().count() > width {
+            if !current.is_empty() {
+                lines.push(std::mem::take(&mut current));
+            }
+            let head: String = word.chars().take(width).collect();
+            word = word.chars().skip(width).collect();
+            lines.push(head);
+        }
+        if current.is_empty() {
+            current = word;
+        } else if current.chars().count() + 1 + word.chars().count() <= width {
+            current.push(' ');
+            current.push_str(&word);
+        } else {
+            lines.push(std::mem::replace(&mut current, word));
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    
+    lines
+        .chunks(lines_per_page.max(1) as usize)
+        .map(|chunk| LineDisplayPage { lines: chunk.to_vec() })
+        .collect()
 }
 
-#[derive(CandidType, Deserialize, Clone, Debug)]
-pub struct UserStakes {
-    pub stakes: Vec<StakeInfo>,
-    pub total_staked: u64,
+#[update]
+fn icrc21_canister_call_consent_message(request: ConsentMessageRequest) -> Result<ConsentInfo, Icrc21Error> {
+    let caller = ic_cdk::caller();
+    let unsupported = |description: String| Icrc21Error::UnsupportedCanisterCall(ErrorInfo { description });
+    
+    let text = match request.method.as_str() {
+        "deposit" => {
+            let args: DepositRequest = candid::decode_one(&request.arg)
+                .map_err(|e| unsupported(format!("Failed to decode deposit arguments: {}", e)))?;
+            deposit_consent_text(&args)
+        }
+        "withdraw" => {
+            let args: WithdrawRequest = candid::decode_one(&request.arg)
+                .map_err(|e| unsupported(format!("Failed to decode withdraw arguments: {}", e)))?;
+            withdraw_consent_text(&caller, &args)
+        }
+        method => return Err(unsupported(format!("No consent message for method {}", method))),
+    }
+    .map_err(|description| Icrc21Error::ConsentMessageUnavailable(ErrorInfo { description }))?;
+    
+    let consent_message = match request.user_preferences.device_spec {
+        Some(DisplayMessageType::LineDisplay { characters_per_line, lines_per_page }) => {
+            ConsentMessage::LineDisplayMessage {
+                pages: paginate_consent_text(&text, characters_per_line, lines_per_page),
+            }
+        }
+        _ => ConsentMessage::GenericDisplayMessage(text),
+    };
+    
+    // Only English messages are available
+    Ok(ConsentInfo {
+        consent_message,
+        metadata: ConsentMessageMetadata {
+            language: "en".to_string(),
+            utc_offset_minutes: request.user_preferences.metadata.utc_offset_minutes,
+        },
+    })
 }
 
-#[derive(CandidType, Deserialize)]
-pub struct DepositRequest {
-    pub amount: u64,
-    pub lock_period_days: u32,
+// An origin is scheme://host[:port] with no path, e.g. "https://app.example.com"
+fn validate_origin(origin: &str) -> Result<(), String> {
+    let rest = origin.strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"))
+        .ok_or("Origin must start with https:// or http://".to_string())?;
+    
+    if rest.is_empty() || rest.contains('/') || rest.contains(char::is_whitespace) {
+        return Err("Origin must be scheme://host[:port] without a path".to_string());
+    }
+    Ok(())
 }
 
-#[derive(CandidType, Deserialize)]
-pub struct WithdrawRequest {
-    pub stake_index: usize,
+#[update]
+fn add_trusted_origin(origin: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    validate_origin(&origin)?;
+    
+    TRUSTED_ORIGINS.with(|origins| {
+        let mut origins = origins.borrow_mut();
+        if origins.contains(&origin) {
+            return Err("Origin is already trusted".to_string());
+        }
+        origins.push(origin.clone());
+        Ok(format!("Added trusted origin {}", origin))
+    })
 }
 
-thread_local! {
-    static STAKES: RefCell<HashMap<Principal, UserStakes>> = RefCell::new(HashMap::new());
-    static TOTAL_POOL_AMOUNT: RefCell<u64> = RefCell::new(0);
-    static NEXT_SUBACCOUNT_NONCE: RefCell<u64> = RefCell::new(1);
-    static AUTHORIZED_PRINCIPALS: RefCell<Vec<Principal>> = RefCell::new(Vec::new());
-    static CANISTER_BALANCE: RefCell<u64> = RefCell::new(1_000_000_000_000); // Example balance for testing
+#[update]
+fn remove_trusted_origin(origin: String) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    TRUSTED_ORIGINS.with(|origins| {
+        let mut origins = origins.borrow_mut();
+        match origins.iter().position(|o| *o == origin) {
+            Some(index) => {
+                origins.remove(index);
+                Ok(format!("Removed trusted origin {}", origin))
+            }
+            None => Err("Origin is not trusted".to_string()),
+        }
+    })
 }
 
-#[init]
-fn init() {
-    let caller = ic_cdk::caller();
-    AUTHORIZED_PRINCIPALS.with(|auth| {
-        auth.borrow_mut().push(caller);
-    });
+#[update]
+fn icrc28_trusted_origins() -> Icrc28TrustedOriginsResponse {
+    Icrc28TrustedOriginsResponse {
+        trusted_origins: TRUSTED_ORIGINS.with(|origins| origins.borrow().clone()),
+    }
 }
 
-fn get_current_time() -> u64 {
-    ic_cdk::api::time() / 1_000_000_000  // Convert from nanoseconds to seconds
+#[query]
+fn icrc10_supported_standards() -> Vec<SupportedStandard> {
+    let standard = |name: &str, url: &str| SupportedStandard {
+        name: name.to_string(),
+        url: url.to_string(),
+    };
+    vec![
+        standard("ICRC-10", "https://github.com/dfinity/ICRC/blob/main/ICRCs/ICRC-10/ICRC-10.md"),
+        standard("ICRC-21", "https://github.com/dfinity/wg-identity-authentication/blob/main/topics/ICRC-21/icrc_21_consent_msg.md"),
+        standard("ICRC-28", "https://github.com/dfinity/wg-identity-authentication/blob/main/topics/icrc_28_trusted_origins.md"),
+    ]
 }
 
-fn is_authorized(caller: &Principal) -> bool {
-    AUTHORIZED_PRINCIPALS.with(|auth| {
-        auth.borrow().contains(caller)
-    })
+// Leaves and inner nodes use distinct prefixes so a node can never be passed off as a leaf
+fn merkle_leaf(principal: &Principal, weight: u64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update([0x00]);
+    hasher.update([principal.as_slice().len() as u8]);
+    hasher.update(principal.as_slice());
+    hasher.update(weight.to_be_bytes());
+    hasher.finalize().into()
 }
 
-fn generate_subaccount(caller: &Principal) -> Subaccount {
-    let nonce = NEXT_SUBACCOUNT_NONCE.with(|n| {
-        let current = *n.borrow();
-        *n.borrow_mut() = current + 1;
-        current
-    });
-    
+fn merkle_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
     let mut hasher = Sha256::new();
-    hasher.update(caller.as_slice());
-    hasher.update(nonce.to_be_bytes());
-    hasher.update(b"staking_pool");  // Add a unique identifier to the hash
+    hasher.update([0x01]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// An odd node at the end of a level is carried up unchanged
+fn merkle_next_level(level: &[[u8; 32]]) -> Vec<[u8; 32]> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => merkle_node(left, right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+fn merkle_root(leaves: &[(Principal, u64)]) -> [u8; 32] {
+    let mut level: Vec<[u8; 32]> = leaves.iter().map(|(p, w)| merkle_leaf(p, *w)).collect();
+    if level.is_empty() {
+        return [0u8; 32];
+    }
+    while level.len() > 1 {
+        level = merkle_next_level(&level);
+    }
+    level[0]
+}
+
+fn merkle_proof(leaves: &[(Principal, u64)], mut index: usize) -> Vec<ProofStep> {
+    let mut level: Vec<[u8; 32]> = leaves.iter().map(|(p, w)| merkle_leaf(p, *w)).collect();
+    let mut proof = Vec::new();
     
-    let hash = hasher.finalize();
-    let mut subaccount = [0u8; 32];
-    subaccount.copy_from_slice(&hash[..32]);  // Only take the first 32 bytes
+    while level.len() > 1 {
+        let sibling_index = index ^ 1;
+        if sibling_index < level.len() {
+            proof.push(ProofStep {
+                sibling: hex::encode(level[sibling_index]),
+                sibling_is_left: sibling_index < index,
+            });
+        }
+        level = merkle_next_level(&level);
+        index /= 2;
+    }
     
-    subaccount
+    proof
 }
 
-fn get_account_identifier(subaccount: &Subaccount) -> AccountIdentifier {
-    format!("account_{}", hex::encode(subaccount))  // Convert subaccount to string
+// Vote-escrow weight: amount scaled by remaining lock time, decaying linearly to zero at unlock
+fn stake_voting_power(stake: &StakeInfo, at_time: u64) -> u64 {
+    if at_time < stake.stake_time {
+        return 0;
+    }
+    let remaining = stake.unlock_time.saturating_sub(at_time).min(MAX_LOCK_SECONDS);
+    (stake.amount as u128 * remaining as u128 / MAX_LOCK_SECONDS as u128) as u64
+}
+
+fn account_voting_power(user_stakes: &UserStakes, at_time: u64) -> u64 {
+    user_stakes.stakes.iter().map(|stake| stake_voting_power(stake, at_time)).sum()
+}
+
+fn voting_weights(at_time: u64) -> Vec<(Principal, u64)> {
+    STAKES.with(|stakes| {
+        stakes.borrow()
+            .iter()
+            .map(|(principal, user_stakes)| (*principal, account_voting_power(user_stakes, at_time)))
+            .filter(|(_, weight)| *weight > 0)
+            .collect()
+    })
+}
+
+// Computed from the stakes held now; stakes already withdrawn do not count for past times
+#[query]
+fn get_voting_power(principal: Principal, at_time: Option<u64>) -> u64 {
+    let owner = account_owner(principal);
+    let at_time = at_time.unwrap_or_else(get_current_time);
+    STAKES.with(|stakes| {
+        stakes.borrow()
+            .get(&owner)
+            .map_or(0, |user_stakes| account_voting_power(user_stakes, at_time))
+    })
 }
 
 #[update]
-async fn deposit(request: DepositRequest) -> Result<String, String> {
+fn create_snapshot() -> Result<SnapshotInfo, String> {
     let caller = ic_cdk::caller();
-    
-    // Validate lock period
-    if ![90, 180, 360].contains(&request.lock_period_days) {
-        return Err("Invalid lock period. Must be 90, 180, or 360 days".to_string());
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
     }
     
-    // Validate amount
-    if request.amount < ICP_FEE {
-        return Err(format!("Amount must be at least {} e8s to cover fees", ICP_FEE));
-    }
+    let current_time = get_current_time();
+    let leaves = voting_weights(current_time);
+    let id = NEXT_SNAPSHOT_ID.with(|n| {
+        let current = *n.borrow();
+        *n.borrow_mut() = current + 1;
+        current
+    });
+    let snapshot = Snapshot {
+        id,
+        created_at: current_time,
+        root: merkle_root(&leaves),
+        total_weight: leaves.iter().map(|(_, weight)| weight).sum(),
+        leaves,
+    };
+    let info = snapshot_info(&snapshot);
     
-    // Check canister balance (simulated)
-    let available_balance = CANISTER_BALANCE.with(|balance| *balance.borrow());
-    if request.amount > available_balance {
-        return Err("Insufficient canister balance for deposit".to_string());
+    SNAPSHOTS.with(|snapshots| {
+        let mut snapshots = snapshots.borrow_mut();
+        if snapshots.len() >= MAX_SNAPSHOTS {
+            snapshots.remove(0);
+        }
+        snapshots.push(snapshot);
+    });
+    
+    Ok(info)
+}
+
+fn snapshot_info(snapshot: &Snapshot) -> SnapshotInfo {
+    SnapshotInfo {
+        id: snapshot.id,
+        created_at: snapshot.created_at,
+        root: hex::encode(snapshot.root),
+        total_weight: snapshot.total_weight,
+        leaf_count: snapshot.leaves.len() as u64,
+    }
+}
+
+#[query]
+fn get_snapshots() -> Vec<SnapshotInfo> {
+    SNAPSHOTS.with(|snapshots| snapshots.borrow().iter().map(snapshot_info).collect())
+}
+
+#[query]
+fn get_snapshot_proof(principal: Principal, snapshot_id: u64) -> Result<SnapshotProof, String> {
+    SNAPSHOTS.with(|snapshots| {
+        let snapshots = snapshots.borrow();
+        let snapshot = snapshots.iter()
+            .find(|snapshot| snapshot.id == snapshot_id)
+            .ok_or("Snapshot not found".to_string())?;
+        
+        // Leaves are keyed by account owner; a principal linked after the snapshot keeps its own leaf
+        let find_leaf = |key: Principal| snapshot.leaves.binary_search_by_key(&key, |(p, _)| *p).ok();
+        let leaf_index = find_leaf(account_owner(principal))
+            .or_else(|| find_leaf(principal))
+            .ok_or("Principal has no voting weight in this snapshot".to_string())?;
+        
+        Ok(SnapshotProof {
+            snapshot_id,
+            root: hex::encode(snapshot.root),
+            principal: snapshot.leaves[leaf_index].0,
+            weight: snapshot.leaves[leaf_index].1,
+            leaf_index: leaf_index as u64,
+            proof: merkle_proof(&snapshot.leaves, leaf_index),
+        })
+    })
+}
+
+fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// `details` must be a JSON object; it is wrapped with the event kind and timestamp
+fn enqueue_alert(kind: &str, details: String) {
+    let enabled = WEBHOOK_CONFIG.with(|config| config.borrow().as_ref().is_some_and(|c| c.enabled));
+    if !enabled {
+        return;
     }
     
-    // Generate unique subaccount for this stake
-    let stake_subaccount = generate_subaccount(&caller);
-    let account_id = get_account_identifier(&stake_subaccount);
+    let current_time = get_current_time();
+    let id = NEXT_WEBHOOK_EVENT_ID.with(|n| {
+        let current = *n.borrow();
+        *n.borrow_mut() = current + 1;
+        current
+    });
+    let body = format!(
+        "{{\"id\":{},\"event\":\"{}\",\"timestamp\":{},\"canister\":\"{}\",\"details\":{}}}",
+        id, kind, current_time, ic_cdk::id(), details
+    );
     
-    // Simulate transfer (in real implementation, this would be actual ICP transfer)
-    CANISTER_BALANCE.with(|balance| {
-        *balance.borrow_mut() -= request.amount;
+    WEBHOOK_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        if queue.len() >= MAX_WEBHOOK_QUEUE {
+            queue.remove(0);
+        }
+        queue.push(WebhookEvent {
+            id,
+            kind: kind.to_string(),
+            body,
+            created_at: current_time,
+            attempts: 0,
+            next_attempt_at: current_time,
+        });
     });
+}
+
+// Sends the oldest due alert; failures are retried with exponential backoff
+async fn deliver_next_webhook(current_time: u64) {
+    use ic_cdk::api::management_canister::http_request::{
+        http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, TransformContext,
+    };
     
-    let current_time = get_current_time();
-    let unlock_time = current_time + (request.lock_period_days as u64 * 24 * 60 * 60);
+    let Some(config) = WEBHOOK_CONFIG.with(|config| config.borrow().clone()).filter(|c| c.enabled) else {
+        return;
+    };
+    let Some(event) = WEBHOOK_QUEUE.with(|queue| {
+        queue.borrow().iter().find(|event| event.next_attempt_at <= current_time).cloned()
+    }) else {
+        return;
+    };
     
-    let stake_info = StakeInfo {
-        amount: request.amount,
-        lock_period_days: request.lock_period_days,
-        stake_time: current_time,
-        unlock_time,
-        subaccount: stake_subaccount,
-        account_id: account_id.clone(),
+    let request = CanisterHttpRequestArgument {
+        url: config.url,
+        max_response_bytes: Some(WEBHOOK_MAX_RESPONSE_BYTES),
+        method: HttpMethod::POST,
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            // Lets the receiver drop the duplicate POSTs sent by each replica
+            HttpHeader { name: "Idempotency-Key".to_string(), value: format!("{}-{}", ic_cdk::id(), event.id) },
+        ],
+        body: Some(event.body.clone().into_bytes()),
+        transform: Some(TransformContext::from_name("webhook_transform".to_string(), vec![])),
     };
+    let result = http_request(request, WEBHOOK_CYCLES).await;
     
-    // Update state
-    STAKES.with(|stakes| {
-        let mut stakes_map = stakes.borrow_mut();
-        let user_stakes = stakes_map.entry(caller).or_insert(UserStakes {
-            stakes: Vec::new(),
-            total_staked: 0,
-        });
+    let delivered = matches!(&result, Ok((response,)) if response.status >= 200u32 && response.status < 300u32);
+    WEBHOOK_QUEUE.with(|queue| {
+        let mut queue = queue.borrow_mut();
+        let Some(index) = queue.iter().position(|e| e.id == event.id) else {
+            return;
+        };
+        if delivered {
+            queue.remove(index);
+            return;
+        }
         
-        user_stakes.stakes.push(stake_info);
-        user_stakes.total_staked += request.amount;
-    });
-    
-    TOTAL_POOL_AMOUNT.with(|total| {
-        *total.borrow_mut() += request.amount;
+        let entry = &mut queue[index];
+        entry.attempts += 1;
+        if entry.attempts >= MAX_WEBHOOK_ATTEMPTS {
+            ic_cdk::println!("Dropping webhook event {} after {} attempts", entry.id, entry.attempts);
+            queue.remove(index);
+        } else {
+            entry.next_attempt_at = get_current_time() + 60 * (1u64 << entry.attempts);
+        }
     });
-    
-    Ok(format!(
-        "Successfully deposited {} e8s for {} days. Account: {}", 
-        request.amount, request.lock_period_days, account_id
-    ))
+}
+
+// Replicas must agree on the response, so only the status code is kept
+#[query]
+fn webhook_transform(args: ic_cdk::api::management_canister::http_request::TransformArgs) -> ic_cdk::api::management_canister::http_request::HttpResponse {
+    ic_cdk::api::management_canister::http_request::HttpResponse {
+        status: args.response.status,
+        headers: Vec::new(),
+        body: Vec::new(),
+    }
 }
 
 #[update]
-async fn withdraw(request: WithdrawRequest) -> Result<String, String> {
+fn set_webhook_config(config: Option<WebhookConfig>) -> Result<String, String> {
     let caller = ic_cdk::caller();
-    let current_time = get_current_time();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
     
-    let (amount, _subaccount) = STAKES.with(|stakes| {
-        let mut stakes_map = stakes.borrow_mut();
-        
-        match stakes_map.get_mut(&caller) {
-            Some(user_stakes) => {
-                if request.stake_index >= user_stakes.stakes.len() {
-                    return Err("Invalid stake index".to_string());
-                }
-                
-                let stake = &user_stakes.stakes[request.stake_index];
-                
-                if current_time < stake.unlock_time {
-                    let remaining_time = stake.unlock_time - current_time;
-                    return Err(format!(
-                        "Stake is still locked. Remaining time: {} seconds", 
-                        remaining_time
-                    ));
-                }
-                
-                let amount = stake.amount;
-                let subaccount = stake.subaccount;
-                
-                // Remove stake and update totals
-                user_stakes.stakes.remove(request.stake_index);
-                user_stakes.total_staked -= amount;
-                
-                TOTAL_POOL_AMOUNT.with(|total| {
-                    *total.borrow_mut() -= amount;
-                });
-                
-                Ok((amount, subaccount))
-            }
-            None => Err("No stakes found for user".to_string()),
+    if let Some(config) = &config {
+        if !config.url.starts_with("https://") {
+            return Err("Webhook URL must use https://".to_string());
         }
-    })?;
-    
-    let transfer_amount = amount.saturating_sub(ICP_FEE);
-    
-    if transfer_amount == 0 {
-        return Err("Insufficient amount to cover transfer fee".to_string());
     }
     
-    CANISTER_BALANCE.with(|balance| {
-        *balance.borrow_mut() += transfer_amount;
-    });
-    
-    Ok(format!(
-        "Successfully withdrew {} e8s (fee: {} e8s)", 
-        transfer_amount, ICP_FEE
-    ))
+    WEBHOOK_CONFIG.with(|c| *c.borrow_mut() = config);
+    Ok("Webhook configuration updated".to_string())
 }
 
-#[query]
-fn get_user_stakes(user: Principal) -> Option<UserStakes> {
-    STAKES.with(|stakes| {
-        stakes.borrow().get(&user).cloned()
+#[update]
+fn set_webhook_enabled(enabled: bool) -> Result<String, String> {
+    let caller = ic_cdk::caller();
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    WEBHOOK_CONFIG.with(|config| match config.borrow_mut().as_mut() {
+        Some(config) => {
+            config.enabled = enabled;
+            Ok(format!("Webhook {}", if enabled { "enabled" } else { "disabled" }))
+        }
+        None => Err("No webhook configured".to_string()),
     })
 }
 
 #[query]
-fn get_my_stakes() -> Option<UserStakes> {
+fn get_webhook_status() -> Result<(Option<WebhookConfig>, Vec<WebhookEvent>), String> {
     let caller = ic_cdk::caller();
-    get_user_stakes(caller)
+    if !is_authorized(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    
+    Ok((
+        WEBHOOK_CONFIG.with(|config| config.borrow().clone()),
+        WEBHOOK_QUEUE.with(|queue| queue.borrow().clone()),
+    ))
 }
 
-#[query]
-fn get_pool_info() -> (u64, usize, usize) {
-    let total_amount = TOTAL_POOL_AMOUNT.with(|total| *total.borrow());
-    let total_stakers = STAKES.with(|stakes| stakes.borrow().len());
-    let total_stakes = STAKES.with(|stakes| {
-        stakes.borrow().values().map(|user_stakes| user_stakes.stakes.len()).sum()
-    });
-    (total_amount, total_stakers, total_stakes)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn test_leaves(count: u8) -> Vec<(Principal, u64)> {
+        (0..count).map(|i| (Principal::from_slice(&[i, 0xAB]), 1_000 * (i as u64 + 1))).collect()
+    }
+    
+    fn verify_proof(leaf: &(Principal, u64), proof: &[ProofStep], root: &[u8; 32]) -> bool {
+        let mut node = merkle_leaf(&leaf.0, leaf.1);
+        for step in proof {
+            let sibling: [u8; 32] = hex::decode(&step.sibling).unwrap().try_into().unwrap();
+            node = if step.sibling_is_left {
+                merkle_node(&sibling, &node)
+            } else {
+                merkle_node(&node, &sibling)
+            };
+        }
+        node == *root
+    }
+    
+    #[test]
+    fn merkle_proofs_verify_against_root_for_odd_leaf_counts() {
+        for count in [1u8, 3, 5, 7, 9] {
+            let leaves = test_leaves(count);
+            let root = merkle_root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, index);
+                assert!(verify_proof(leaf, &proof, &root), "leaf {} of {}", index, count);
+            }
+        }
+    }
+    
+    #[test]
+    fn merkle_proof_rejects_a_changed_weight() {
+        let leaves = test_leaves(5);
+        let root = merkle_root(&leaves);
+        let proof = merkle_proof(&leaves, 4);
+        assert!(!verify_proof(&(leaves[4].0, leaves[4].1 + 1), &proof, &root));
+    }
+    
+    #[test]
+    fn stake_record_round_trips_every_stored_field() {
+        let mut subaccount = [0u8; 32];
+        for (i, byte) in subaccount.iter_mut().enumerate() {
+            *byte = i as u8 * 7;
+        }
+        let stake = StakeInfo {
+            id: u64::MAX - 1,
+            amount: 123_456_789_012,
+            lock_period_days: 360,
+            stake_time: 1_704_067_200,
+            unlock_time: u64::MAX,
+            subaccount,
+            label: Some("kept in the side list".to_string()),
+        };
+        
+        let record = encode_stake(&stake);
+        assert_eq!(record.len(), STAKE_RECORD_SIZE);
+        
+        let decoded = decode_stake(&record);
+        assert_eq!(decoded.id, stake.id);
+        assert_eq!(decoded.amount, stake.amount);
+        assert_eq!(decoded.lock_period_days, stake.lock_period_days);
+        assert_eq!(decoded.stake_time, stake.stake_time);
+        assert_eq!(decoded.unlock_time, stake.unlock_time);
+        assert_eq!(decoded.subaccount, stake.subaccount);
+        assert_eq!(decoded.label, None);
+    }
+    
+    fn page_lines(pages: &[LineDisplayPage]) -> Vec<Vec<&str>> {
+        pages.iter().map(|page| page.lines.iter().map(String::as_str).collect()).collect()
+    }
+    
+    #[test]
+    fn consent_text_wraps_words_and_splits_pages() {
+        let pages = paginate_consent_text("aaa bbb ccc dddddddddd", 7, 2);
+        assert_eq!(page_lines(&pages), vec![vec!["aaa bbb", "ccc"], vec!["ddddddd", "ddd"]]);
+    }
+    
+    #[test]
+    fn consent_text_lines_never_exceed_the_width() {
+        let text = "Deposit 1.5 ICP into the staking pool, locked for 90 days. Withdrawing after unlock costs 0.0001 ICP.";
+        for width in [1u16, 5, 12, 40] {
+            let pages = paginate_consent_text(text, width, 3);
+            for page in &pages {
+                assert!(page.lines.len() <= 3);
+                assert!(page.lines.iter().all(|line| line.chars().count() <= width as usize));
+            }
+            // Every character survives, in order; only the whitespace between words changes
+            let rejoined: String = pages.iter()
+                .flat_map(|page| page.lines.iter())
+                .flat_map(|line| line.chars())
+                .filter(|c| !c.is_whitespace())
+                .collect();
+            assert_eq!(rejoined, text.split_whitespace().collect::<String>());
+        }
+    }
+    
+    #[test]
+    fn consent_text_treats_zero_limits_as_one() {
+        let pages = paginate_consent_text("ab", 0, 0);
+        assert_eq!(page_lines(&pages), vec![vec!["a"], vec!["b"]]);
+    }
+    
+    fn test_lien(stake_id: u64, amount: u64) -> Lien {
+        Lien {
+            stake_id,
+            owner: Principal::from_slice(&[1]),
+            lien_holder: Principal::from_slice(&[2]),
+            amount,
+            created_at: 0,
+        }
+    }
+    
+    #[test]
+    fn state_digest_is_independent_of_mutation_order() {
+        let digest = || STATE_DIGEST.with(|digest| *digest.borrow());
+        
+        insert_lien(test_lien(1, 100));
+        insert_lien(test_lien(2, 200));
+        let both = digest();
+        
+        remove_lien(1);
+        remove_lien(2);
+        assert_eq!(digest(), (0, 0));
+        
+        insert_lien(test_lien(2, 200));
+        insert_lien(test_lien(1, 50));
+        insert_lien(test_lien(1, 100));  // Replacing a record swaps its digest
+        assert_eq!(digest(), both);
+    }
+    
+    // Thread-locals are per test thread, so each test starts from an empty pool
+    fn set_pool(limit: ConcentrationLimit, holdings: &[(Principal, u64)]) {
+        CONCENTRATION_LIMIT.with(|l| *l.borrow_mut() = Some(limit));
+        TOTAL_POOL_AMOUNT.with(|total| *total.borrow_mut() = holdings.iter().map(|(_, amount)| amount).sum());
+        STAKES.with(|stakes| {
+            let mut stakes = stakes.borrow_mut();
+            for (owner, amount) in holdings {
+                stakes.insert(*owner, UserStakes { stakes: Vec::new(), total_staked: *amount });
+            }
+        });
+    }
+    
+    #[test]
+    fn concentration_cap_allows_exactly_the_headroom() {
+        let whale = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        // 10% cap: with 900 held by others, (x) / (900 + x) <= 10% allows at most 100
+        set_pool(ConcentrationLimit { max_share_bps: 1_000, min_pool_amount: 0 }, &[(other, 900)]);
+        
+        assert!(check_concentration(&whale, 100).is_ok());
+        let error = check_concentration(&whale, 101).unwrap_err();
+        assert!(error.contains("1000 bps"), "{}", error);
+        assert!(error.contains(&format_amount(100)), "{}", error);
+    }
+    
+    #[test]
+    fn concentration_cap_counts_existing_holdings() {
+        let whale = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        // 25% cap: (50 + x) / (950 + x) <= 25% allows at most 250
+        set_pool(ConcentrationLimit { max_share_bps: 2_500, min_pool_amount: 0 }, &[(whale, 50), (other, 900)]);
+        
+        assert!(check_concentration(&whale, 250).is_ok());
+        assert!(check_concentration(&whale, 251).is_err());
+    }
+    
+    #[test]
+    fn concentration_cap_does_not_block_an_empty_or_small_pool() {
+        let whale = Principal::from_slice(&[1]);
+        set_pool(ConcentrationLimit { max_share_bps: 1_000, min_pool_amount: 0 }, &[]);
+        assert!(check_concentration(&whale, 1_000_000).is_ok());
+        
+        let other = Principal::from_slice(&[2]);
+        set_pool(ConcentrationLimit { max_share_bps: 1_000, min_pool_amount: 10_000 }, &[(other, 900)]);
+        assert!(check_concentration(&whale, 9_099).is_ok());
+        assert!(check_concentration(&whale, 9_100).is_err());
+    }
+    
+    #[test]
+    fn link_concentration_compares_the_combined_account_to_the_pool() {
+        let owner = Principal::from_slice(&[1]);
+        let linked = Principal::from_slice(&[2]);
+        let other = Principal::from_slice(&[3]);
+        // 25% cap over a pool of 1000: linking moves no funds into the pool, so 250 is the limit
+        set_pool(ConcentrationLimit { max_share_bps: 2_500, min_pool_amount: 0 }, &[(owner, 150), (linked, 100), (other, 750)]);
+        assert!(check_link_concentration(&owner, 250).is_ok());
+        
+        set_pool(ConcentrationLimit { max_share_bps: 2_500, min_pool_amount: 0 }, &[(owner, 150), (linked, 101), (other, 749)]);
+        assert!(check_link_concentration(&owner, 251).is_err());
+    }
 }
 
 // Export Candid interface (remove ic_ledger_types and export_candid)